@@ -8,13 +8,14 @@ struct ProfileAttr {
     level: u32,
     name: String,
     value: u16,
+    awaiting: bool,
 }
 
 
 impl ProfileAttr {
     fn new(name: String) -> Self
     {
-        Self { level: 0, name , value: 0 }
+        Self { level: 0, name , value: 0, awaiting: false }
     }
 
     fn parse(&mut self, meta: syn::meta::ParseNestedMeta) -> syn::Result<()>
@@ -28,6 +29,13 @@ impl ProfileAttr {
         } else if meta.path.is_ident("value") {
             self.value = meta.value()?.parse::<syn::LitInt>()?.base10_parse::<u16>()?;
             Ok(())
+        } else if meta.path.is_ident("awaiting") {
+            // Either `awaiting` or `awaiting = true`
+            self.awaiting = match meta.value() {
+                Ok(value) => value.parse::<syn::LitBool>()?.value(),
+                Err(_) => true,
+            };
+            Ok(())
         } else {
             Err(meta.error("unsupported profile property"))
         }
@@ -51,11 +59,28 @@ pub fn extrae_profile(args: TokenStream, item: TokenStream) -> TokenStream
 
     let fn_name = attrs.name.clone();
     let value = attrs.value;
+    let awaiting = attrs.awaiting;
+
+    if awaiting && fn_sig.asyncness.is_none() {
+        return syn::Error::new_spanned(&fn_sig, "awaiting is only valid for async functions")
+            .to_compile_error()
+            .into();
+    }
 
-    let expanded = quote! {
-        #fn_vis #fn_sig {
-            extrae_rs::instrument_function!(#fn_name, #value);
-            #fn_block
+    // A guard in an async function would move between threads with
+    // the future, so the body is polled inside an Instrumented future.
+    let expanded = if fn_sig.asyncness.is_some() {
+        quote! {
+            #fn_vis #fn_sig {
+                extrae_rs::instrument_async!(#fn_name, #value, #awaiting, async move #fn_block)
+            }
+        }
+    } else {
+        quote! {
+            #fn_vis #fn_sig {
+                extrae_rs::instrument_function!(#fn_name, #value);
+                #fn_block
+            }
         }
     };
 
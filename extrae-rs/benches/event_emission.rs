@@ -0,0 +1,43 @@
+//! Compare the per-event cost of the different core id modes.
+//!
+//! Run with: cargo bench --features bench --bench event_emission [iterations]
+
+use extrae_rs::{CoreMode, EventEntry};
+
+fn measure<F: FnMut()>(iterations: u64, mut f: F) -> f64
+{
+    // Warm up caches, OnceLocks and the vDSO.
+    for _ in 0..iterations / 10 {
+        f();
+    }
+
+    let start = std::time::Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    start.elapsed().as_nanos() as f64 / iterations as f64
+}
+
+fn main()
+{
+    let iterations: u64 = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(10_000_000);
+
+    println!("{:<10} {:<10} {:>12} {:>12}", "requested", "effective", "core ns", "event ns");
+
+    for mode in CoreMode::ALL {
+        let effective = mode.set_global();
+
+        let core_ns = measure(iterations, || {
+            std::hint::black_box(effective.core_id());
+        });
+
+        let event_ns = measure(iterations, || {
+            std::hint::black_box(EventEntry::bench_new(1, 1));
+        });
+
+        println!("{:<10} {:<10} {:>12.2} {:>12.2}", mode, effective, core_ns, event_ns);
+    }
+}
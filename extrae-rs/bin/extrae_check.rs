@@ -0,0 +1,49 @@
+use std::env;
+
+fn usage(program: &str) -> !
+{
+    eprintln!("Usage: {} [COUNTER...]", program);
+    eprintln!("Check which performance counters work in this machine.");
+    eprintln!("Without arguments all the generic, cache and PMU counters are checked.");
+    std::process::exit(1);
+}
+
+fn main()
+{
+    let args: Vec<String> = env::args().collect();
+
+    if args.iter().skip(1).any(|arg| arg.starts_with('-')) {
+        usage(&args[0]);
+    }
+
+    let counters: Vec<String> = if args.len() > 1 {
+        args[1..].to_vec()
+    } else {
+        extrae_rs::all_counters()
+    };
+
+    match extrae_rs::perf_event_paranoid() {
+        Some(paranoid) => println!("perf_event_paranoid: {}", paranoid),
+        None => println!("perf_event_paranoid: unknown (perf events not available?)"),
+    }
+    println!();
+
+    let mut failed = 0;
+
+    for counter in counters.iter() {
+        match extrae_rs::check_counter(counter) {
+            Ok(()) => println!("{:<25} ok", counter),
+            Err(error) => {
+                println!("{:<25} FAILED: {}", counter, error);
+                failed += 1;
+            }
+        }
+    }
+
+    println!();
+    println!("{} of {} counters available", counters.len() - failed, counters.len());
+
+    if failed > 0 {
+        std::process::exit(2);
+    }
+}
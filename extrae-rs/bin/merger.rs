@@ -0,0 +1,46 @@
+use std::env;
+
+use extrae_rs::Merger;
+
+fn usage(program: &str) -> !
+{
+    eprintln!("Usage: {} TRACEDIR [--threads N] [--recover]", program);
+    std::process::exit(1);
+}
+
+fn main()
+{
+    let args: Vec<String> = env::args().collect();
+
+    let mut tracedir: Option<std::path::PathBuf> = None;
+    let mut threads: usize = 1;
+    let mut recover = false;
+
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--threads" | "-j" => {
+                threads = iter.next()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or_else(|| usage(&args[0]));
+            },
+            "--recover" => recover = true,
+            _ if tracedir.is_none() => tracedir = Some(std::path::PathBuf::from(arg)),
+            _ => usage(&args[0]),
+        }
+    }
+
+    let tracedir = tracedir.unwrap_or_else(|| usage(&args[0]));
+
+    println!("Merging {} with {} threads", tracedir.display(), threads);
+
+    let merged = Merger::new(&tracedir, threads, recover)
+        .and_then(|mut merger| merger.create_prv(&tracedir));
+
+    if let Err(error) = merged {
+        eprintln!("Error merging {}: {}", tracedir.display(), error);
+        std::process::exit(1);
+    }
+
+    println!("Done");
+}
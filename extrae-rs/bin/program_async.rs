@@ -0,0 +1,51 @@
+use extrae_rs::extrae_profile;
+
+use tokio::task;
+use tokio::time::{self, Duration};
+
+#[extrae_profile]
+fn compute(i: u64) -> u64
+{
+    (0..10_000u64).fold(i, |acc, j| std::hint::black_box(acc.wrapping_add(j)))
+}
+
+// Every poll is a region in the thread that polls the future
+#[extrae_profile]
+async fn step(i: u64) -> u64
+{
+    time::sleep(Duration::from_millis(2)).await;
+    compute(i)
+}
+
+// The polls that return Pending are marked with async_task:awaiting
+#[extrae_profile(name = "async_task", awaiting)]
+async fn async_task(steps: u64) -> Result<u64, String>
+{
+    let mut total = 0;
+    for i in 0..steps {
+        total += step(i).await;
+        task::yield_now().await;
+    }
+
+    if total == 0 {
+        return Err("Nothing done".to_string());
+    }
+    Ok(total)
+}
+
+// Polled by the main thread, which finalizes the trace
+#[extrae_profile]
+async fn run()
+{
+    let handles: Vec<_> = (0..6).map(|_| task::spawn(async_task(10))).collect();
+
+    for handle in handles {
+        println!("Task result: {:?}", handle.await.unwrap());
+    }
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 3)]
+async fn main()
+{
+    run().await;
+}
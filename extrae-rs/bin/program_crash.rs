@@ -0,0 +1,62 @@
+use extrae_rs::extrae_profile;
+
+#[extrae_profile]
+fn myfunction(i: u32) -> u32
+{
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    i
+}
+
+#[extrae_profile]
+fn crash()
+{
+    panic!("Crash requested");
+}
+
+// Run with EXTRAE_FLUSH_ON_CRASH=true to get the trace of the threads
+// running when the main thread panics. With the "caught" argument only
+// a worker thread panics and the program ends normally, with "abort"
+// the main thread aborts instead of panicking.
+fn main()
+{
+    println!("Start Program");
+
+    if std::env::args().nth(1).as_deref() == Some("caught") {
+        println!("Call function!: {}", myfunction(1));
+
+        let worker = std::thread::spawn(|| {
+            myfunction(2);
+            crash();
+        });
+        assert!(worker.join().is_err());
+
+        println!("Call function!: {}", myfunction(3));
+        return;
+    }
+
+    for i in 1..5 {
+        println!("Call function!: {}", myfunction(i));
+    }
+
+    let (sender, receiver) = std::sync::mpsc::channel::<()>();
+
+    let worker = std::thread::spawn(move || {
+        let mut i = 0;
+        while receiver.try_recv().is_err() {
+            myfunction(i);
+            i += 1;
+        }
+    });
+
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    if std::env::args().nth(1).as_deref() == Some("abort") {
+        std::process::abort();
+    }
+
+    crash();
+
+    // Never reached
+    sender.send(()).unwrap();
+    worker.join().unwrap();
+}
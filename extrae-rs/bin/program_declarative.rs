@@ -1,4 +1,6 @@
-use extrae_rs::{instrument_function, GlobalInfo, ThreadInfo};
+use extrae_rs::instrument_function;
+#[cfg(feature = "profiling")]
+use extrae_rs::{GlobalInfo, ThreadInfo};
 
 fn myfunction()
 {
@@ -21,10 +23,13 @@ fn myfunction3()
 fn main() -> nix::Result<()>
 {
     println!("Start Program");
-    GlobalInfo::register_event_name("Event1", Some(file!()), Some(line!()), Some(10));
-    ThreadInfo::emplace_event(10, 1);
+    #[cfg(feature = "profiling")]
+    {
+        GlobalInfo::register_event_name("Event1", Some(file!()), Some(line!()), Some(10));
+        ThreadInfo::emplace_event(10, 1);
 
-    ThreadInfo::emplace_event(10, 0);
+        ThreadInfo::emplace_event(10, 0);
+    }
 
     myfunction();
 
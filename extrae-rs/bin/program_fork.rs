@@ -0,0 +1,39 @@
+use extrae_rs::extrae_profile;
+
+#[extrae_profile]
+fn myfunction(i: u32) -> u32
+{
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    i
+}
+
+// A fork based worker: the child trace is written in a fork_<pid>
+// subdirectory of the parent trace directory.
+#[extrae_profile]
+fn main() -> nix::Result<()>
+{
+    println!("Start Program");
+
+    for i in 1..5 {
+        println!("Parent function!: {}", myfunction(i));
+    }
+
+    match unsafe { nix::unistd::fork() }? {
+        nix::unistd::ForkResult::Child => {
+            for i in 1..5 {
+                println!("Child function!: {}", myfunction(i));
+            }
+        },
+        nix::unistd::ForkResult::Parent { child } => {
+            for i in 1..5 {
+                println!("Parent function!: {}", myfunction(i));
+            }
+
+            let status = nix::sys::wait::waitpid(child, None)?;
+            assert!(matches!(status, nix::sys::wait::WaitStatus::Exited(_, 0)), "Child failed: {:?}", status);
+        }
+    }
+
+    println!("Done");
+    Ok(())
+}
@@ -0,0 +1,87 @@
+#[cfg(feature = "profiling")]
+use extrae_rs::{ExtraeFutureExt, ExtraeStreamExt};
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio::time::{self, Duration};
+
+// The adapter always emits events, so without profiling it is
+// replaced by the future or the stream itself.
+#[cfg(not(feature = "profiling"))]
+trait ExtraeFutureExt: Sized {
+    fn extrae_instrument(self, _name: &'static str) -> Self
+    {
+        self
+    }
+}
+
+#[cfg(not(feature = "profiling"))]
+impl<F: std::future::Future> ExtraeFutureExt for F {}
+
+#[cfg(not(feature = "profiling"))]
+trait ExtraeStreamExt: Sized {
+    fn extrae_instrument(self, _name: &'static str) -> Self
+    {
+        self
+    }
+}
+
+#[cfg(not(feature = "profiling"))]
+impl<S: Stream> ExtraeStreamExt for S {}
+
+/// A stream of the values received in a channel.
+struct Received(mpsc::Receiver<u64>);
+
+impl Stream for Received {
+    type Item = u64;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<u64>>
+    {
+        self.0.poll_recv(cx)
+    }
+}
+
+async fn next<S: Stream + Unpin>(stream: &mut S) -> Option<S::Item>
+{
+    std::future::poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await
+}
+
+async fn producer(sender: mpsc::Sender<u64>)
+{
+    for i in 0..10 {
+        time::sleep(Duration::from_millis(2)).await;
+        sender.send(i).await.unwrap();
+    }
+}
+
+#[extrae_rs::extrae_profile]
+async fn run()
+{
+    let (sender, receiver) = mpsc::channel(4);
+
+    // A future from another function, and a stream
+    let handle = task::spawn(producer(sender).extrae_instrument("producer"));
+    let mut stream = Received(receiver).extrae_instrument("received_stream");
+
+    let mut total = 0;
+    while let Some(value) = next(&mut stream).await {
+        total += value;
+    }
+
+    // A future built with a combinator
+    let later = async { time::sleep(Duration::from_millis(5)).await; 1 };
+    total += task::spawn(later.extrae_instrument("later")).await.unwrap();
+
+    handle.await.unwrap();
+    println!("Total: {}", total);
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 2)]
+async fn main()
+{
+    run().await;
+}
@@ -0,0 +1,41 @@
+#[cfg(feature = "profiling")]
+use extrae_rs::ExtraeLayer;
+
+use tracing::info;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
+use tokio::task;
+use tokio::time::{self, Duration};
+
+#[tracing::instrument]
+async fn layer_task(id: u32) {
+    info!(value = 3, "Layer task started");
+    verbose_step(id).await;
+    time::sleep(Duration::from_millis(100)).await;
+}
+
+// Filtered out by the LevelFilter, so it is not in the trace.
+#[tracing::instrument(level = "debug")]
+async fn verbose_step(id: u32) {
+    time::sleep(Duration::from_millis(50)).await;
+}
+
+#[tokio::main]
+async fn main() {
+
+    // The extrae layer composes with the fmt layer, and the level
+    // filter applies to both.
+    let registry = tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr));
+
+    #[cfg(feature = "profiling")]
+    let registry = registry.with(ExtraeLayer::new());
+
+    registry.init();
+
+    let handle1 = task::spawn(layer_task(1));
+    let handle2 = task::spawn(layer_task(2));
+
+    let _ = tokio::join!(handle1, handle2);
+}
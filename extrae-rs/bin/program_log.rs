@@ -0,0 +1,75 @@
+#[cfg(feature = "profiling")]
+use extrae_rs::ExtraeLogger;
+
+use extrae_rs::extrae_profile;
+
+/// The usual output, to show the forwarding.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool
+    {
+        metadata.level() <= log::Level::Info
+    }
+
+    fn log(&self, record: &log::Record<'_>)
+    {
+        eprintln!("[{}] {}", record.level(), record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+#[extrae_profile]
+fn work(i: u64) -> u64
+{
+    log::debug!("Working on {}", i);
+    (0..10_000u64).fold(i, |acc, j| std::hint::black_box(acc.wrapping_add(j)))
+}
+
+#[extrae_profile]
+fn run()
+{
+    log::info!("Starting");
+
+    let total: u64 = (0..5).map(work).sum();
+    if total > 0 {
+        log::warn!("Total is {}", total);
+    }
+
+    // Records without location, like the ones bridged from other
+    // logging libraries
+    for i in 0..3 {
+        log::logger().log(&log::Record::builder()
+            .level(log::Level::Info)
+            .target("bridge")
+            .args(format_args!("Bridged {}", i))
+            .build());
+    }
+    log::logger().log(&log::Record::builder()
+        .level(log::Level::Info)
+        .target("bridge")
+        .args(format_args!("Bridged"))
+        .build());
+
+    // Ignored by the max_level
+    log::trace!("Done");
+}
+
+fn main()
+{
+    #[cfg(feature = "profiling")]
+    ExtraeLogger::new()
+        .max_level(log::LevelFilter::Debug)
+        .forward_to(Box::new(StderrLogger))
+        .init()
+        .expect("A logger was already set");
+
+    #[cfg(not(feature = "profiling"))]
+    {
+        log::set_logger(&StderrLogger).expect("A logger was already set");
+        log::set_max_level(log::LevelFilter::Info);
+    }
+
+    run();
+}
@@ -0,0 +1,34 @@
+#[cfg(feature = "profiling")]
+use extrae_rs::RuntimeSampler;
+
+use tokio::task;
+use tokio::time::{self, Duration};
+
+async fn worker(steps: u64) -> u64 {
+    let mut total = 0;
+    for i in 0..steps {
+        // Some busy time and some parked time in every worker
+        total += (0..100_000u64).fold(i, |acc, j| std::hint::black_box(acc.wrapping_add(j)));
+        time::sleep(Duration::from_millis(5)).await;
+    }
+    total
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 2)]
+async fn main() {
+
+    extrae_rs::ConfigBuilder::new()
+        .runtime_metrics_period(5)
+        .apply()
+        .expect("Invalid profiler configuration");
+
+    // Sample until the end of main
+    #[cfg(feature = "profiling")]
+    let _sampler = RuntimeSampler::start(&tokio::runtime::Handle::current());
+
+    let handles: Vec<_> = (0..8).map(|_| task::spawn(worker(20))).collect();
+
+    for handle in handles {
+        println!("Worker result: {}", handle.await.unwrap());
+    }
+}
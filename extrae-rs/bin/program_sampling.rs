@@ -0,0 +1,49 @@
+use extrae_rs::extrae_profile;
+
+#[inline(never)]
+fn busy_work(iterations: u64) -> u64
+{
+    (0..iterations).fold(0u64, |acc, i| std::hint::black_box(acc.wrapping_mul(31).wrapping_add(i)))
+}
+
+#[extrae_profile]
+fn compute(i: u64) -> u64
+{
+    busy_work(1 << 20) + i
+}
+
+#[extrae_profile]
+fn run()
+{
+    std::thread::scope(|s| {
+        for thread in 1..3 {
+            s.spawn(move || {
+                for i in 0..20 {
+                    println!("Thread: {} compute: {}", thread, compute(i));
+                }
+            });
+        }
+    });
+
+    for i in 0..20 {
+        println!("Call compute: {}", compute(i));
+    }
+}
+
+fn main() -> nix::Result<()>
+{
+    println!("Start Program");
+
+    // Before the first event; the environment (EXTRAE_SAMPLING...)
+    // can still change these.
+    extrae_rs::ConfigBuilder::new()
+        .sampling("task-clock")
+        .sampling_period(100_000)
+        .apply()
+        .expect("Invalid profiler configuration");
+
+    run();
+
+    println!("Done");
+    Ok(())
+}
@@ -1,6 +1,8 @@
+#[cfg(feature = "profiling")]
 use extrae_rs::ExtraeSubscriber;
 
 use tracing::info;
+#[cfg(feature = "profiling")]
 use tracing::subscriber::set_global_default;
 use tokio::task;
 use tokio::time::{self, Duration};
@@ -13,9 +15,14 @@ async fn task1() {
     //info!("Task 1 completed");
 }
 
-#[tracing::instrument(name = "custom_task2", skip(_param))]
+#[tracing::instrument(
+    name = "custom_task2",
+    skip(_param),
+    fields(size = _param.len(), retries = 0, cached = _param.is_empty(), stage = tracing::field::Empty)
+)]
 async fn task2(_param: &str) {
     //info!(param, "Task 2 started");
+    tracing::Span::current().record("stage", "sleeping");
     time::sleep(Duration::from_millis(300)).await;
     //info!("Task 2 completed");
 }
@@ -25,8 +32,11 @@ async fn task2(_param: &str) {
 async fn main() {
 
     // Set up a subscriber that logs to stdout
-    let subscriber = ExtraeSubscriber::new();
-    set_global_default(subscriber).expect("Could not set global default subscriber");
+    #[cfg(feature = "profiling")]
+    {
+        let subscriber = ExtraeSubscriber::new();
+        set_global_default(subscriber).expect("Could not set global default subscriber");
+    }
 
     // Run tasks concurrently
     let handle1 = task::spawn(task1());
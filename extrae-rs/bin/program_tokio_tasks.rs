@@ -0,0 +1,62 @@
+#[cfg(feature = "profiling")]
+use extrae_rs::ExtraeSubscriber;
+
+use std::future::Future;
+
+#[cfg(feature = "profiling")]
+use tracing::subscriber::set_global_default;
+use tokio::task::{self, JoinHandle};
+use tokio::time::{self, Duration};
+
+/// Tokio creates a runtime.spawn span for every task only when it is
+/// built with `RUSTFLAGS="--cfg tokio_unstable"`, and only then the
+/// tasks can have a name. With a normal build the program runs, but
+/// the trace has no task events.
+#[track_caller]
+fn spawn_named<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(tokio_unstable)]
+    return task::Builder::new().name(name).spawn(future).expect("Failed to spawn the task");
+
+    #[cfg(not(tokio_unstable))]
+    {
+        let _ = name;
+        tokio::spawn(future)
+    }
+}
+
+async fn worker(steps: u64) {
+    for _ in 0..steps {
+        time::sleep(Duration::from_millis(20)).await;
+        task::yield_now().await;
+    }
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 2)]
+async fn main() {
+
+    extrae_rs::ConfigBuilder::new()
+        .tokio_tasks(true)
+        .apply()
+        .expect("Invalid profiler configuration");
+
+    #[cfg(feature = "profiling")]
+    {
+        let subscriber = ExtraeSubscriber::new();
+        set_global_default(subscriber).expect("Could not set global default subscriber");
+    }
+
+    let handles: Vec<_> = (0..4)
+        .map(|i| spawn_named("worker", worker(5 + i)))
+        .collect();
+
+    let other = tokio::spawn(worker(3));
+
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    other.await.unwrap();
+}
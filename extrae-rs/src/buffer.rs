@@ -1,12 +1,16 @@
 #![allow(dead_code)]
 
-use crate::bufferinfo;
+use crate::{bufferinfo, codec};
 
 pub struct Buffer {
     name: String,
+    /// Number of events to keep in memory before a flush.
+    max_entries: usize,
     path: std::path::PathBuf,
     file: Option<std::fs::File>,
     info: bufferinfo::BufferInfo,
+    /// Header of the last event already flushed.
+    last_flushed: Option<crate::event::EventHeader>,
 }
 
 impl Buffer {
@@ -16,13 +20,18 @@ impl Buffer {
         tid: &std::thread::ThreadId,
         name: &str,
         path: std::path::PathBuf,
-        start_gtime: &std::time::Duration
+        start_gtime: &std::time::Duration,
+        codec: codec::Codec,
+        max_entries: usize
     ) -> Self {
+        let max_entries = max_entries.max(1);
         Self {
             name: name.to_string(),
+            max_entries,
             path,
             file: None,
-            info: bufferinfo::BufferInfo::new(id, &tid, &start_gtime)
+            info: bufferinfo::BufferInfo::new(id, tid, start_gtime, codec, max_entries),
+            last_flushed: None
         }
     }
 
@@ -48,7 +57,7 @@ impl Buffer {
 
         let info = bufferinfo::BufferInfo::from_file(&mut file);
 
-        Self { name, path, file: None, info }
+        Self { name, max_entries: info.len().max(1), path, file: None, info, last_flushed: None }
     }
 
 
@@ -62,32 +71,87 @@ impl Buffer {
         // I do this because some threads may not create traces, so no
         // file creation is needed.
         if self.file.is_none() {
+            // The directory may not exist yet in a forked child
+            if let Some(dir) = self.path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+
             self.file = Some(
                 std::fs::OpenOptions::new()
                     .write(true)
                     .create(true) // Creates the file if it does not exist
-                    .open(&self.path).unwrap()
+                    .truncate(true)
+                    .open(&self.path)?
             );
         }
 
+        self.last_flushed = self.last_header();
         self.info.flush_to_file(self.file.as_mut().unwrap())
     }
 
+    /// Drop the pending events and forget the file without writing
+    /// anything. Used in forked children for the buffers inherited
+    /// from the parent.
+    pub(crate) fn discard(&mut self)
+    {
+        self.info.entries.clear();
+        self.file = None;
+    }
+
     pub fn emplace_event(&mut self, id: u16, value: u32)
     {
         self.info.emplace_event(id, value);
+        self.flush_if_full();
     }
 
     pub fn emplace_events(&mut self, entries: &[(u16, u32)])
     {
         self.info.emplace_events(entries);
+        self.flush_if_full();
+    }
+
+    pub(crate) fn emplace_events_at(&mut self, hdr: crate::event::EventHeader, entries: &[(u16, u32)])
+    {
+        self.info.emplace_events_at(hdr, entries);
+        self.flush_if_full();
+    }
+
+    /// Header of the last event in the buffer, including the flushed
+    /// ones.
+    pub(crate) fn last_header(&self) -> Option<crate::event::EventHeader>
+    {
+        self.info.entries.last().map_or(self.last_flushed, |entry| Some(entry.hdr))
+    }
+
+    /// Write the events to the file when the buffer reaches
+    /// max_entries, this keeps the memory usage bounded.
+    #[inline]
+    fn flush_if_full(&mut self)
+    {
+        if self.info.len() >= self.max_entries {
+            self.flush_or_drop();
+        }
+    }
+
+    /// Flush the events or drop them when they cannot be written (a
+    /// full disk, unordered times). The traced program keeps running
+    /// and the error is reported once.
+    pub(crate) fn flush_or_drop(&mut self)
+    {
+        if let Err(error) = self.flush() {
+            crate::perf::warn_once(format_args!(
+                "cannot write the events to {} ({}), dropping them",
+                self.path.display(), error
+            ));
+            self.info.entries.clear();
+        }
     }
 
 }
 
 impl Drop for Buffer {
     fn drop(&mut self) {
-        self.flush().expect("Failed to flush buffer to file on drop");
+        self.flush_or_drop();
     }
 }
 
@@ -109,7 +173,9 @@ mod profiler {
             &std::thread::current().id(),
             "",
             path.clone(),
-            &std::time::Duration::default()
+            &std::time::Duration::default(),
+            codec::Codec::Raw,
+            1024
         );
 
         buff.emplace_event(1, 1);
@@ -131,7 +197,9 @@ mod profiler {
             &std::thread::current().id(),
             "",
             path.clone(),
-            &std::time::Duration::default()
+            &std::time::Duration::default(),
+            codec::Codec::Raw,
+            1024
         );
 
         // Assert that the file is NOT created
@@ -151,7 +219,9 @@ mod profiler {
             &std::thread::current().id(),
             "",
             path.clone(),
-            &std::time::Duration::default()
+            &std::time::Duration::default(),
+            codec::Codec::Raw,
+            1024
         );
 
         buff.emplace_event(1, 1);
@@ -193,7 +263,9 @@ mod profiler {
                 &std::thread::current().id(),
                 "",
                 path.clone(),
-                &std::time::Duration::default()
+                &std::time::Duration::default(),
+                codec::Codec::Raw,
+                1024
             );
 
             buff.emplace_event(0, 1);
@@ -221,4 +293,111 @@ mod profiler {
 
         std::fs::remove_file(path).unwrap();
     }
+
+    #[test]
+    fn buffer_serialize_multi_encoded()
+    {
+        let path = std::path::PathBuf::from_str("/tmp/buffer_serialize_multi_encoded").unwrap();
+
+        { // Same as buffer_serialize_multi, but every flush is a chunk
+            let mut buff = Buffer::new(
+                1,
+                &std::thread::current().id(),
+                "",
+                path.clone(),
+                &std::time::Duration::default(),
+                codec::Codec::Lz4,
+                1024
+            );
+
+            buff.emplace_event(0, 1);
+            buff.emplace_event(1, 2);
+            buff.flush().unwrap();
+
+            buff.emplace_events(&[(2, 3), (3, 4)]);
+            buff.flush().unwrap();
+
+            buff.emplace_event(4, 5);
+            buff.emplace_event(5, 6);
+        }
+
+        let mut file = std::fs::File::open(&path).unwrap();
+        let imported_info = crate::BufferInfo::from_file(&mut file);
+
+        assert_eq!(imported_info.header.codec(), codec::Codec::Lz4);
+        assert_eq!(imported_info.header.total_flushed, 6);
+        for i in 0..6 {
+            assert_eq!(imported_info.entries[i].info.id, i as u16);
+            assert_eq!(imported_info.entries[i].info.value, (i + 1) as u32);
+        }
+        assert_eq!(imported_info.entries[2].hdr, imported_info.entries[3].hdr);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn buffer_flush_when_full()
+    {
+        let path = std::path::PathBuf::from_str("/tmp/buffer_flush_when_full").unwrap();
+
+        {
+            let mut buff = Buffer::new(
+                1,
+                &std::thread::current().id(),
+                "",
+                path.clone(),
+                &std::time::Duration::default(),
+                codec::Codec::Raw,
+                4
+            );
+
+            for i in 0..10 {
+                buff.emplace_event(i, i as u32 + 1);
+            }
+
+            // Two full buffers were written, two events remain.
+            assert_eq!(buff.info.header.total_flushed, 8);
+            assert_eq!(buff.info.len(), 2);
+        }
+
+        let mut file = std::fs::File::open(&path).unwrap();
+        let imported_info = crate::BufferInfo::from_file(&mut file);
+
+        assert_eq!(imported_info.header.total_flushed, 10);
+        for i in 0..10 {
+            assert_eq!(imported_info.entries[i].info.id, i as u16);
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn buffer_flush_error_drops()
+    {
+        // The parent of the trace file is a file, so it cannot be created.
+        let parent = std::path::PathBuf::from_str("/tmp/buffer_flush_error_drops").unwrap();
+        std::fs::write(&parent, "").unwrap();
+
+        {
+            let mut buff = Buffer::new(
+                1,
+                &std::thread::current().id(),
+                "",
+                parent.join("Trace_1.bin"),
+                &std::time::Duration::default(),
+                codec::Codec::Raw,
+                4
+            );
+
+            for i in 0..10 {
+                buff.emplace_event(i, i as u32 + 1);
+            }
+
+            // The full buffers were dropped, not flushed.
+            assert_eq!(buff.info.header.total_flushed, 0);
+            assert_eq!(buff.info.len(), 2);
+        }
+
+        std::fs::remove_file(parent).unwrap();
+    }
 }
@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 
-use crate::event;
+use crate::{codec, event};
 use std::{io::{Read, Seek, Write}, os::unix::fs::FileExt};
 
 #[repr(C)]
@@ -10,20 +10,32 @@ pub(crate) struct TraceHeader {
     pub(crate) tid: std::thread::ThreadId,
     pub(crate) start_gtime: u64,
     pub(crate) total_flushed: u32,
+    /// codec::Codec used to write the events.
+    pub(crate) codec: u32,
 }
 
 impl TraceHeader {
 
-    fn new(id: u32, tid: &std::thread::ThreadId, start_gtime: &std::time::Duration) -> Self
-    {
+    fn new(
+        id: u32,
+        tid: &std::thread::ThreadId,
+        start_gtime: &std::time::Duration,
+        codec: codec::Codec
+    ) -> Self {
         Self {
             id,
             tid: *tid,
             start_gtime: start_gtime.as_secs() ,
-            total_flushed: 0
+            total_flushed: 0,
+            codec: codec as u32
         }
     }
 
+    pub(crate) fn codec(&self) -> codec::Codec
+    {
+        codec::Codec::from_u32(self.codec).expect("Invalid codec in trace header")
+    }
+
     fn as_bytes(&self) ->  &[u8]
     {
         unsafe {
@@ -36,8 +48,8 @@ impl TraceHeader {
 }
 impl std::fmt::Display for TraceHeader {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "id:{} tid:{:?} start_gtime:{} total_flushed:{}",
-            self.id, self.tid, self.start_gtime, self.total_flushed)
+        write!(f, "id:{} tid:{:?} start_gtime:{} total_flushed:{} codec:{}",
+            self.id, self.tid, self.start_gtime, self.total_flushed, self.codec())
     }
 }
 
@@ -48,25 +60,26 @@ pub struct BufferInfo {
 }
 
 impl BufferInfo {
-    const MAX_ENTRIES: usize = (1024 * 1024 + std::mem::size_of::<event::EventEntry>() - 1) / std::mem::size_of::<event::EventEntry>();
 
     pub(crate) fn new(
         id: u32,
         tid: &std::thread::ThreadId,
-        start_gtime: &std::time::Duration
+        start_gtime: &std::time::Duration,
+        codec: codec::Codec,
+        capacity: usize
     ) -> Self {
         Self {
-            header: TraceHeader::new(id, &tid, &start_gtime),
-            entries: Vec::<event::EventEntry>::with_capacity(Self::MAX_ENTRIES)
+            header: TraceHeader::new(id, tid, start_gtime, codec.effective()),
+            entries: Vec::<event::EventEntry>::with_capacity(capacity)
         }
     }
 
     pub fn from_file(file: &mut std::fs::File) -> Self
     {
+        let file_len = file.metadata().expect("Error reading file metadata").len();
         let mut buf_reader = std::io::BufReader::new(file);
 
         const HDRSIZE: usize = std::mem::size_of::<TraceHeader>();
-        const EVTSIZE: usize = std::mem::size_of::<event::EventEntry>();
 
         // Allocate a buffer to read the structs
         let mut tmp = vec![0u8; HDRSIZE];
@@ -76,39 +89,22 @@ impl BufferInfo {
             *(tmp.as_ptr() as *const TraceHeader) 
         };
 
-        // Now create a vector for the number of entries in the header
-        // and import the events "IN PLACE". The unsafe code is to
-        // avoid a temporal buffer and coping.
         let n_entries: usize = header.total_flushed as usize;
+
+        // The events are read chunk by chunk.
         let mut entries = Vec::<event::EventEntry>::with_capacity(n_entries);
+        let mut buf_reader = buf_reader.take(file_len.saturating_sub(HDRSIZE as u64));
 
-        buf_reader.read_exact(
-            unsafe {
-                std::slice::from_raw_parts_mut(
-                    entries.as_mut_ptr() as *mut u8,
-                    n_entries * EVTSIZE
-                )
+        while let Some(chunk) = codec::read_chunk(&mut buf_reader, header.codec())
+            .expect("Error reading events from file") {
+                entries.extend(chunk);
             }
-        ).expect("Error reading events from file");
 
-        unsafe {
-            entries.set_len(n_entries);
-        }
+        assert_eq!(entries.len(), n_entries, "Wrong number of events in file");
 
         Self { header, entries }
     }
 
-    fn entries_as_bytes(&self) -> &[u8]
-    {
-        unsafe {
-            // Convert the slice to a slice of bytes
-            std::slice::from_raw_parts(
-                self.entries.as_ptr() as *const u8,
-                self.entries.len() * std::mem::size_of::<event::EventEntry>(),
-            )
-        }
-    }
-
     fn entries_to_file(
         &mut self,
         file: &mut std::fs::File
@@ -117,7 +113,7 @@ impl BufferInfo {
         file.seek(std::io::SeekFrom::End(0))?;
         let mut writer = std::io::BufWriter::new(file);
 
-        writer.write_all(self.entries_as_bytes())?;
+        writer.write_all(&codec::encode_chunk(&self.entries, self.header.codec())?)?;
         writer.flush()?;
 
         self.entries.clear();
@@ -133,14 +129,26 @@ impl BufferInfo {
             return Ok(());
         }
 
+        // The first flush writes the header to reserve its space.
+        if file.metadata()?.len() == 0 {
+            file.write_at(self.header.as_bytes(), 0)?;
+        }
+
         let n_entries: usize = self.entries.len();
+        let end = file.metadata()?.len();
+
+        // The header is updated only after the chunk is completely
+        // written. If the process dies in the middle, the header
+        // still describes the valid part of the file.
+        if let Err(error) = self.entries_to_file(file) {
+            // Remove the partial chunk, the next ones follow the valid part.
+            let _ = file.set_len(end);
+            return Err(error);
+        }
 
         self.header.total_flushed += n_entries as u32;
-
         file.write_at(self.header.as_bytes(), 0)?;
 
-        self.entries_to_file(file)?;
-
         Ok(())
     }
 
@@ -151,19 +159,23 @@ impl BufferInfo {
 
     pub(crate) fn emplace_events(&mut self, entries: &[(u16, u32)])
     {
-        let hdr = crate::event::EventHeader::new();
+        self.emplace_events_at(crate::event::EventHeader::new(), entries);
+    }
 
+    /// Add events with a given header, for the events that happened
+    /// before they are stored (the perf samples).
+    pub(crate) fn emplace_events_at(&mut self, hdr: event::EventHeader, entries: &[(u16, u32)])
+    {
         for &entry in entries.iter() {
             self.entries.push(
-                event::EventEntry { hdr: hdr.clone(), info: entry.into() }
+                event::EventEntry { hdr, info: entry.into() }
             );
         }
     }
 
-    pub(crate) fn is_full(&self) -> bool
+    pub(crate) fn len(&self) -> usize
     {
-        assert!(self.entries.len() <= BufferInfo::MAX_ENTRIES);
-        self.entries.len() == BufferInfo::MAX_ENTRIES
+        self.entries.len()
     }
 
     pub(crate) fn is_empty(&self) -> bool
@@ -186,7 +198,7 @@ impl std::fmt::Display for BufferInfo {
             writeln!(f, "{}", entry)?;
         }
 
-        write!(f, "\n")
+        writeln!(f)
     }
 }
 
@@ -211,7 +223,9 @@ mod profiler {
         let info = BufferInfo::new(
             1,
             &std::thread::current().id(),
-            &std::time::Duration::default()
+            &std::time::Duration::default(),
+            codec::Codec::Raw,
+            16
         );
 
         assert_eq!(info.header.total_flushed, 0);
@@ -225,7 +239,9 @@ mod profiler {
         let mut info = BufferInfo::new(
             1,
             &std::thread::current().id(),
-            &std::time::Duration::default()
+            &std::time::Duration::default(),
+            codec::Codec::Raw,
+            16
         );
 
         info.emplace_event(1, 1);
@@ -246,7 +262,9 @@ mod profiler {
         let mut info = BufferInfo::new(
             1,
             &std::thread::current().id(),
-            &std::time::Duration::default()
+            &std::time::Duration::default(),
+            codec::Codec::Raw,
+            16
         );
         assert!(info.is_empty());
 
@@ -264,8 +282,7 @@ mod profiler {
         assert_eq!(info[0].hdr, info[2].hdr);
         assert_eq!(info[0].hdr, info[3].hdr);
 
-        // Check that 3 entries does not full the buffer
-        assert!(!info.is_full());
+        assert_eq!(info.len(), 4);
     }
 
 
@@ -278,7 +295,9 @@ mod profiler {
         let mut info = BufferInfo::new(
             1,
             &std::thread::current().id(),
-            &std::time::Duration::default()
+            &std::time::Duration::default(),
+            codec::Codec::Raw,
+            16
         );
 
         info.emplace_event(1, 1);
@@ -300,7 +319,7 @@ mod profiler {
         std::fs::remove_file(path).unwrap();
 
         assert!(!imported_info.is_empty());
-        assert!(!imported_info.is_full());
+        assert_eq!(imported_info.len(), 6);
 
         // Tests imported values
         assert_eq!(imported_info[0].info, (1, 1).into());
@@ -28,19 +28,25 @@ pub struct BufferSet {
 
     pub(crate) start_system_time: std::time::Duration,
     pub(crate) trace_directory_path: std::path::PathBuf,
+    codec: crate::codec::Codec,
+    buffer_entries: usize,
 }
 
 impl BufferSet {
 
     pub fn new(
         start_system_time: std::time::Duration,
-        trace_directory_path: std::path::PathBuf
+        trace_directory_path: std::path::PathBuf,
+        codec: crate::codec::Codec,
+        buffer_size: usize
     ) -> Self {
         Self {
             threadid_map: Arc::new(RwLock::new(HashMap::new())),
             threads_counter: atomic::AtomicU32::new(0),
             start_system_time,
-            trace_directory_path
+            trace_directory_path,
+            codec,
+            buffer_entries: buffer_size.div_ceil(std::mem::size_of::<crate::EventEntry>())
         }
     }
 
@@ -86,7 +92,9 @@ impl BufferSet {
             &tid,
             name,
             self.trace_directory_path.join(format!("Trace_{}.bin", id)),
-            &self.start_system_time
+            &self.start_system_time,
+            self.codec,
+            self.buffer_entries
         )
     }
 
@@ -101,6 +109,7 @@ impl BufferSet {
     pub fn save_buffer_id(&mut self, buffer: &buffer::Buffer)
     {
         println!(" Disposing: {} {:?}", buffer.id(), buffer.tid());
+
         match self.threadid_map
             .write()
             .expect("Failed to get threadid_map write lock")
@@ -117,20 +126,6 @@ impl BufferSet {
     /// Write the trace.row file on exit.
     pub fn create_row(&self, trace_dir: &std::path::Path) -> std::io::Result<()>
     {
-        let hostname = nix::unistd::gethostname()
-            .expect("Error getting hostname")
-            .into_string().expect("Failed to convert hostname to string");
-
-        // Get the total number of cores in the system
-        let ncores = {
-            match nix::unistd::sysconf(
-                nix::unistd::SysconfVar::_NPROCESSORS_CONF
-            ) {
-                Ok(Some(value)) => value,
-                _ => panic!("Error getting the system number of cores"),
-            }
-        };
-
         let nthreads = self.threads_counter.load(atomic::Ordering::Relaxed);
 
         if self.threadid_map.read().expect("Error getting threadid_map read lock").len()
@@ -147,7 +142,30 @@ impl BufferSet {
             "The number of thread ids does not match with the total stored in the threadid_map"
         );
 
-        let rowfile = std::fs::File::create(trace_dir.join("Trace.row")).unwrap();
+        self.write_row(trace_dir)
+    }
+
+    /// Write the trace.row file with all the threads created until
+    /// now, including the ones still running.
+    pub(crate) fn write_row(&self, trace_dir: &std::path::Path) -> std::io::Result<()>
+    {
+        let hostname = nix::unistd::gethostname()
+            .expect("Error getting hostname")
+            .into_string().expect("Failed to convert hostname to string");
+
+        // Get the total number of cores in the system
+        let ncores = {
+            match nix::unistd::sysconf(
+                nix::unistd::SysconfVar::_NPROCESSORS_CONF
+            ) {
+                Ok(Some(value)) => value,
+                _ => panic!("Error getting the system number of cores"),
+            }
+        };
+
+        let nthreads = self.threads_counter.load(atomic::Ordering::Relaxed);
+
+        let rowfile = std::fs::File::create(trace_dir.join("Trace.row"))?;
         let mut writer = std::io::BufWriter::new(rowfile);
 
         writeln!(writer, "LEVEL CPU SIZE {}", ncores)?;
@@ -0,0 +1,116 @@
+use std::sync::OnceLock;
+
+use serde::Deserialize;
+
+/// Clock used for the event timestamps.
+///
+/// The timestamps are always nanoseconds since the profiler
+/// initialization; the clock only changes how the time advances.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Clock {
+    /// CLOCK_MONOTONIC (default), the one used by std::time::Instant.
+    #[default]
+    Monotonic,
+    /// CLOCK_MONOTONIC_RAW, not affected by the NTP frequency
+    /// adjustments.
+    MonotonicRaw,
+    /// CLOCK_BOOTTIME, like monotonic but it also counts the time the
+    /// system was suspended.
+    Boottime,
+}
+
+impl Clock {
+    fn clock_id(self) -> nix::time::ClockId
+    {
+        match self {
+            Clock::Monotonic => nix::time::ClockId::CLOCK_MONOTONIC,
+            Clock::MonotonicRaw => nix::time::ClockId::CLOCK_MONOTONIC_RAW,
+            Clock::Boottime => nix::time::ClockId::CLOCK_BOOTTIME,
+        }
+    }
+
+    /// The clock id for perf_event_open, so the samples use the same
+    /// time than the events.
+    pub(crate) fn raw_clock_id(self) -> nix::libc::clockid_t
+    {
+        self.clock_id().as_raw()
+    }
+
+    /// Current time of this clock in nanoseconds.
+    #[inline]
+    pub fn now(self) -> u64
+    {
+        let time = nix::time::clock_gettime(self.clock_id())
+            .expect("Failed to read the clock");
+
+        time.tv_sec() as u64 * 1_000_000_000 + time.tv_nsec() as u64
+    }
+
+    /// Set the clock used for all the events. This only works before
+    /// the first event is created; returns the clock in use.
+    pub fn set_global(self) -> Clock
+    {
+        start(self).0
+    }
+}
+
+impl std::fmt::Display for Clock {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Clock::Monotonic => "monotonic",
+            Clock::MonotonicRaw => "monotonicraw",
+            Clock::Boottime => "boottime",
+        };
+        f.pad(name)
+    }
+}
+
+/// The clock in use and its value when the trace started.
+fn start(clock: Clock) -> &'static (Clock, u64)
+{
+    static START: OnceLock<(Clock, u64)> = OnceLock::new();
+    START.get_or_init(|| (clock, clock.now()))
+}
+
+/// The clock used for the events.
+pub(crate) fn global() -> Clock
+{
+    start(Clock::default()).0
+}
+
+/// Convert a time of the global clock (like the perf sample times) to
+/// nanoseconds since the trace begins.
+pub(crate) fn since_start(time_ns: u64) -> u64
+{
+    time_ns.saturating_sub(start(Clock::default()).1)
+}
+
+/// Nanoseconds since the trace begins. This is what the event headers
+/// use.
+#[inline]
+pub(crate) fn elapsed_ns() -> u64
+{
+    let (clock, start) = *start(Clock::default());
+    clock.now() - start
+}
+
+
+#[cfg(test)]
+mod profiler {
+
+    use super::*;
+
+    #[test]
+    fn clocks_advance()
+    {
+        for clock in [Clock::Monotonic, Clock::MonotonicRaw, Clock::Boottime] {
+            let first = clock.now();
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            assert!(clock.now() - first >= 1_000_000, "Clock {} failed", clock);
+        }
+
+        let first = elapsed_ns();
+        assert!(elapsed_ns() >= first);
+    }
+}
@@ -0,0 +1,479 @@
+use std::io::{Read, Write};
+
+use serde::Deserialize;
+
+use crate::event;
+
+/// Encoding used for the events in the Trace_N.bin files.
+///
+/// The codec is stored in the trace header, so the readers
+/// (TraceIterator, BufferInfo::from_file) decode the files
+/// transparently.
+///
+/// With all the codecs every flush is written as a chunk with a
+/// ChunkHeader, so partially written files can be recovered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// The EventEntry structs are written as they are in memory (default).
+    #[default]
+    Raw = 0,
+    /// Chunks of varint delta encoded events.
+    Compact = 1,
+    /// Compact chunks compressed with lz4.
+    Lz4 = 2,
+    /// Compact chunks compressed with zstd (needs the zstd feature).
+    Zstd = 3,
+}
+
+impl Codec {
+    pub(crate) fn from_u32(value: u32) -> std::io::Result<Self>
+    {
+        match value {
+            0 => Ok(Codec::Raw),
+            1 => Ok(Codec::Compact),
+            2 => Ok(Codec::Lz4),
+            3 => Ok(Codec::Zstd),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Unknown trace codec: {}", value)
+            )),
+        }
+    }
+
+    /// Get the codec that will be really used when this one is
+    /// requested. Zstd degrades to Lz4 when the feature is disabled.
+    pub fn effective(self) -> Codec
+    {
+        match self {
+            Codec::Zstd if !cfg!(feature = "zstd") => Codec::Lz4,
+            codec => codec,
+        }
+    }
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Codec::Raw => "raw",
+            Codec::Compact => "compact",
+            Codec::Lz4 => "lz4",
+            Codec::Zstd => "zstd",
+        };
+        f.pad(name)
+    }
+}
+
+/// Header written before every chunk.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct ChunkHeader {
+    pub(crate) n_events: u32,
+    /// Size of the encoded events before compression
+    pub(crate) raw_len: u32,
+    /// Size of the data stored in the file after this header.
+    pub(crate) stored_len: u32,
+    /// crc32 of the stored data
+    pub(crate) checksum: u32,
+}
+
+impl ChunkHeader {
+    const SIZE: usize = std::mem::size_of::<ChunkHeader>();
+
+    fn to_bytes(self) -> [u8; Self::SIZE]
+    {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..4].copy_from_slice(&self.n_events.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.raw_len.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.stored_len.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self
+    {
+        Self {
+            n_events: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            raw_len: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            stored_len: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            checksum: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+        }
+    }
+
+    /// Check the sizes before allocating anything. A corrupted header
+    /// is handled like a truncated file.
+    fn check(&self, codec: Codec, available: u64) -> std::io::Result<()>
+    {
+        let n_events = self.n_events as u64;
+        let raw_len = self.raw_len as u64;
+        let stored_len = self.stored_len as u64;
+
+        let valid = stored_len <= available && match codec {
+            Codec::Raw => raw_len == stored_len
+                && raw_len == n_events * std::mem::size_of::<event::EventEntry>() as u64,
+            Codec::Compact => raw_len == stored_len
+                && (n_events * MIN_ENCODED_EVENT..=n_events * MAX_ENCODED_EVENT).contains(&raw_len),
+            // lz4 cannot compress more than 255:1
+            Codec::Lz4 => raw_len <= stored_len * 255
+                && (n_events * MIN_ENCODED_EVENT..=n_events * MAX_ENCODED_EVENT).contains(&raw_len),
+            Codec::Zstd => (n_events * MIN_ENCODED_EVENT..=n_events * MAX_ENCODED_EVENT).contains(&raw_len),
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("Trace chunk header {:?} exceeds the {} bytes left", self, available)
+            ))
+        }
+    }
+}
+
+/// Size limits of an event encoded by encode_events: the flags byte
+/// and at least one byte per varint, and at most the full varints.
+const MIN_ENCODED_EVENT: u64 = 4;
+const MAX_ENCODED_EVENT: u64 = 1 + 10 + 3 + 3 + 5;
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64)
+{
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(input: &[u8], pos: &mut usize) -> std::io::Result<u64>
+{
+    let mut value: u64 = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = *input.get(*pos).ok_or_else(|| std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "Truncated varint in trace chunk"
+        ))?;
+        *pos += 1;
+
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+
+        shift += 7;
+        if shift >= 64 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Varint overflow in trace chunk"
+            ));
+        }
+    }
+}
+
+fn invalid_data(message: String) -> std::io::Error
+{
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn entries_as_bytes(entries: &[event::EventEntry]) -> &[u8]
+{
+    unsafe {
+        std::slice::from_raw_parts(
+            entries.as_ptr() as *const u8,
+            std::mem::size_of_val(entries),
+        )
+    }
+}
+
+fn entries_from_bytes(input: &[u8], n_events: usize) -> std::io::Result<Vec<event::EventEntry>>
+{
+    if input.len() != n_events * std::mem::size_of::<event::EventEntry>() {
+        return Err(invalid_data(format!("Invalid raw chunk size {}", input.len())));
+    }
+
+    let mut entries = Vec::<event::EventEntry>::with_capacity(n_events);
+
+    // Copy the events "IN PLACE"
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            input.as_ptr(),
+            entries.as_mut_ptr() as *mut u8,
+            input.len()
+        );
+        entries.set_len(n_events);
+    }
+
+    Ok(entries)
+}
+
+/// Encode the events with varint delta timestamps.
+///
+/// Every chunk starts with time 0 and no core, so chunks can be
+/// decoded independently. Every event starts with a flags byte; bit 0
+/// means that the core changed and follows the time delta.
+///
+/// The events of a buffer must be ordered in time, an event older
+/// than the previous one is an error.
+fn encode_events(entries: &[event::EventEntry]) -> std::io::Result<Vec<u8>>
+{
+    let mut out = Vec::<u8>::with_capacity(entries.len() * 4);
+    let mut last_time: u64 = 0;
+    let mut last_core: Option<u16> = None;
+
+    for entry in entries {
+        let core_changed = last_core != Some(entry.hdr.core);
+        out.push(core_changed as u8);
+
+        let delta = entry.hdr.time.checked_sub(last_time)
+            .ok_or_else(|| invalid_data(format!(
+                "Unordered event in trace chunk: {} after {}", entry.hdr.time, last_time
+            )))?;
+
+        write_varint(&mut out, delta);
+        if core_changed {
+            write_varint(&mut out, entry.hdr.core as u64);
+        }
+        write_varint(&mut out, entry.info.id as u64);
+        write_varint(&mut out, entry.info.value as u64);
+
+        last_time = entry.hdr.time;
+        last_core = Some(entry.hdr.core);
+    }
+
+    Ok(out)
+}
+
+fn decode_events(input: &[u8], n_events: usize) -> std::io::Result<Vec<event::EventEntry>>
+{
+    let invalid = |what: &str| invalid_data(format!("Invalid {} in trace chunk", what));
+
+    let mut entries = Vec::<event::EventEntry>::with_capacity(n_events);
+    let mut pos: usize = 0;
+    let mut time: u64 = 0;
+    let mut core: u16 = 0;
+
+    for _ in 0..n_events {
+        let flags = *input.get(pos).ok_or_else(|| invalid("flags"))?;
+        pos += 1;
+
+        time += read_varint(input, &mut pos)?;
+        if flags & 1 != 0 {
+            core = u16::try_from(read_varint(input, &mut pos)?).map_err(|_| invalid("core"))?;
+        }
+        let id = u16::try_from(read_varint(input, &mut pos)?).map_err(|_| invalid("id"))?;
+        let value = u32::try_from(read_varint(input, &mut pos)?).map_err(|_| invalid("value"))?;
+
+        entries.push(event::EventEntry {
+            hdr: event::EventHeader { time, core },
+            info: event::EventInfo { id, value }
+        });
+    }
+
+    if pos != input.len() {
+        return Err(invalid("length"));
+    }
+
+    Ok(entries)
+}
+
+/// Encode a chunk of events (chunk header included) with the given
+/// codec.
+pub(crate) fn encode_chunk(entries: &[event::EventEntry], codec: Codec) -> std::io::Result<Vec<u8>>
+{
+    let raw: std::borrow::Cow<[u8]> = match codec {
+        Codec::Raw => entries_as_bytes(entries).into(),
+        _ => encode_events(entries)?.into(),
+    };
+
+    let stored: std::borrow::Cow<[u8]> = match codec.effective() {
+        Codec::Raw | Codec::Compact => raw.as_ref().into(),
+        Codec::Lz4 => lz4_flex::block::compress(&raw).into(),
+        #[cfg(feature = "zstd")]
+        Codec::Zstd => zstd::bulk::compress(&raw, 3)
+            .expect("Failed to compress trace chunk")
+            .into(),
+        #[cfg(not(feature = "zstd"))]
+        Codec::Zstd => unreachable!("Zstd codec is not enabled"),
+    };
+
+    let header = ChunkHeader {
+        n_events: entries.len() as u32,
+        raw_len: raw.len() as u32,
+        stored_len: stored.len() as u32,
+        checksum: crc32fast::hash(&stored),
+    };
+
+    let mut out = Vec::<u8>::with_capacity(ChunkHeader::SIZE + stored.len());
+    out.write_all(&header.to_bytes()).unwrap();
+    out.write_all(&stored).unwrap();
+    Ok(out)
+}
+
+/// Read and decode the next chunk from a reader.
+/// Returns Ok(None) on a clean end of file. Truncated or corrupted
+/// chunks return an error.
+///
+/// The reader limit must be the data left in the file, the chunk
+/// header is checked against it before allocating the chunk.
+pub(crate) fn read_chunk<R: Read>(
+    reader: &mut std::io::Take<R>,
+    codec: Codec
+) -> std::io::Result<Option<Vec<event::EventEntry>>> {
+
+    let mut hdr_bytes = [0u8; ChunkHeader::SIZE];
+
+    // Distinguish the clean end of file from a truncated header.
+    let mut read = 0;
+    while read < ChunkHeader::SIZE {
+        match reader.read(&mut hdr_bytes[read..])? {
+            0 if read == 0 => return Ok(None),
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            n => read += n,
+        }
+    }
+
+    let header = ChunkHeader::from_bytes(&hdr_bytes);
+    header.check(codec, reader.limit())?;
+
+    let mut stored = vec![0u8; header.stored_len as usize];
+    reader.read_exact(&mut stored)?;
+
+    if crc32fast::hash(&stored) != header.checksum {
+        return Err(invalid_data("Trace chunk checksum mismatch".to_string()));
+    }
+
+    let raw: Vec<u8> = match codec {
+        Codec::Raw => return entries_from_bytes(&stored, header.n_events as usize).map(Some),
+        Codec::Compact => stored,
+        Codec::Lz4 => lz4_flex::block::decompress(&stored, header.raw_len as usize)
+            .map_err(|e| invalid_data(e.to_string()))?,
+        #[cfg(feature = "zstd")]
+        Codec::Zstd => zstd::bulk::decompress(&stored, header.raw_len as usize)?,
+        #[cfg(not(feature = "zstd"))]
+        Codec::Zstd => return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Trace compressed with zstd, rebuild with the zstd feature"
+        )),
+    };
+
+    decode_events(&raw, header.n_events as usize).map(Some)
+}
+
+
+#[cfg(test)]
+mod profiler {
+
+    use super::*;
+
+    fn sample_entries() -> Vec<event::EventEntry>
+    {
+        [(10, 3, 1, 1), (10, 3, 2, 5), (25, 3, 1, 0), (1000, 7, 3, u32::MAX), (1 << 40, 7, u16::MAX, 0)]
+            .iter()
+            .map(|&(time, core, id, value)| event::EventEntry {
+                hdr: event::EventHeader { time, core },
+                info: event::EventInfo { id, value }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn varint_roundtrip()
+    {
+        let values = [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX];
+        let mut buffer = Vec::new();
+        for &value in values.iter() {
+            write_varint(&mut buffer, value);
+        }
+
+        let mut pos = 0;
+        for &value in values.iter() {
+            assert_eq!(read_varint(&buffer, &mut pos).unwrap(), value);
+        }
+        assert_eq!(pos, buffer.len());
+    }
+
+    #[test]
+    fn chunk_roundtrip()
+    {
+        let entries = sample_entries();
+
+        for codec in [Codec::Raw, Codec::Compact, Codec::Lz4, Codec::Zstd] {
+            let mut data = encode_chunk(&entries, codec).unwrap();
+            data.extend(encode_chunk(&entries[1..3], codec).unwrap());
+
+            let len = data.len() as u64;
+            let mut reader = std::io::Cursor::new(data).take(len);
+            let codec = codec.effective();
+            assert_eq!(read_chunk(&mut reader, codec).unwrap().unwrap(), entries);
+            assert_eq!(read_chunk(&mut reader, codec).unwrap().unwrap(), &entries[1..3]);
+            assert!(read_chunk(&mut reader, codec).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn chunk_truncated()
+    {
+        let data = encode_chunk(&sample_entries(), Codec::Lz4).unwrap();
+        let mut reader = std::io::Cursor::new(&data[..data.len() - 1]).take(data.len() as u64 - 1);
+        assert!(read_chunk(&mut reader, Codec::Lz4).is_err());
+
+        let mut reader = std::io::Cursor::new(&data[..ChunkHeader::SIZE / 2]).take(ChunkHeader::SIZE as u64 / 2);
+        assert!(read_chunk(&mut reader, Codec::Lz4).is_err());
+    }
+
+    #[test]
+    fn chunk_corrupted()
+    {
+        let mut data = encode_chunk(&sample_entries(), Codec::Compact).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+
+        let len = data.len() as u64;
+        let mut reader = std::io::Cursor::new(data).take(len);
+        let error = read_chunk(&mut reader, Codec::Compact).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn chunk_bad_header()
+    {
+        let entries = sample_entries();
+
+        // Sizes bigger than the file or inconsistent with the number
+        // of events fail before allocating.
+        let corruptions: [fn(&mut ChunkHeader); 4] = [
+            |header| header.stored_len = u32::MAX,
+            |header| header.raw_len = u32::MAX,
+            |header| header.n_events = u32::MAX,
+            |header| header.n_events = 0,
+        ];
+
+        for codec in [Codec::Raw, Codec::Compact, Codec::Lz4] {
+            for corrupt in corruptions {
+                let mut data = encode_chunk(&entries, codec).unwrap();
+                let mut header = ChunkHeader::from_bytes(data[..ChunkHeader::SIZE].try_into().unwrap());
+                corrupt(&mut header);
+                data[..ChunkHeader::SIZE].copy_from_slice(&header.to_bytes());
+
+                let len = data.len() as u64;
+                let mut reader = std::io::Cursor::new(data).take(len);
+                let error = read_chunk(&mut reader, codec).unwrap_err();
+                assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof, "{} {:?}", codec, header);
+            }
+        }
+    }
+
+    #[test]
+    fn chunk_unordered()
+    {
+        let mut entries = sample_entries();
+        entries.swap(2, 3);
+
+        let error = encode_chunk(&entries, Codec::Compact).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        // The raw codec stores the absolute times
+        assert!(encode_chunk(&entries, Codec::Raw).is_ok());
+    }
+}
@@ -0,0 +1,241 @@
+use std::sync::OnceLock;
+use std::sync::atomic;
+
+use serde::Deserialize;
+
+/// Method used to obtain the core id stored in every event header.
+///
+/// Getting the cpu id is the most expensive part of an event
+/// emission when `sched_getcpu` is not served by the vDSO. The
+/// cheaper modes fall back to `Syscall` when they are not supported
+/// by the current system, so it is always safe to request them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CoreMode {
+    /// Call `sched_getcpu` for every event (default).
+    #[default]
+    Syscall,
+    /// Read the cpu id from the rseq area registered by glibc (>= 2.35).
+    Rseq,
+    /// Use the `rdpid` (or `rdtscp`) instruction on x86_64.
+    Rdpid,
+}
+
+impl CoreMode {
+    pub const ALL: [CoreMode; 3] = [CoreMode::Syscall, CoreMode::Rseq, CoreMode::Rdpid];
+
+    /// Get the mode that will be really used when this one is
+    /// requested. Unsupported modes degrade to Syscall.
+    pub fn effective(self) -> CoreMode
+    {
+        match self {
+            CoreMode::Rseq if rseq_offset().is_none() => CoreMode::Syscall,
+            CoreMode::Rdpid if !rdpid_supported() && !rdtscp_supported() => CoreMode::Syscall,
+            mode => mode,
+        }
+    }
+
+    /// Set the mode used by all the event headers created from now.
+    /// Returns the effective mode after the fallback.
+    pub fn set_global(self) -> CoreMode
+    {
+        let mode = self.effective();
+        CORE_MODE.store(mode as u8, atomic::Ordering::Relaxed);
+        mode
+    }
+
+    /// Get the mode used to create the event headers.
+    pub fn global() -> CoreMode
+    {
+        Self::from_u8(CORE_MODE.load(atomic::Ordering::Relaxed))
+    }
+
+    fn from_u8(value: u8) -> CoreMode
+    {
+        match value {
+            1 => CoreMode::Rseq,
+            2 => CoreMode::Rdpid,
+            _ => CoreMode::Syscall,
+        }
+    }
+
+    /// Get the current core id using this mode.
+    ///
+    /// The mode is assumed to be an effective one (see
+    /// [`CoreMode::effective`]), but this never fails; the unsupported
+    /// paths return the `sched_getcpu` value.
+    #[inline]
+    pub fn core_id(self) -> u16
+    {
+        match self {
+            CoreMode::Syscall => syscall_core_id(),
+            CoreMode::Rseq => rseq_core_id().unwrap_or_else(syscall_core_id),
+            CoreMode::Rdpid => rdpid_core_id().unwrap_or_else(syscall_core_id),
+        }
+    }
+}
+
+impl std::fmt::Display for CoreMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            CoreMode::Syscall => "syscall",
+            CoreMode::Rseq => "rseq",
+            CoreMode::Rdpid => "rdpid",
+        };
+        f.pad(name)
+    }
+}
+
+static CORE_MODE: atomic::AtomicU8 = atomic::AtomicU8::new(CoreMode::Syscall as u8);
+
+/// Get the core id with the global mode. This is what the event
+/// headers use.
+#[inline]
+pub(crate) fn current_core_id() -> u16
+{
+    CoreMode::global().core_id()
+}
+
+fn syscall_core_id() -> u16
+{
+    u16::try_from(nix::sched::sched_getcpu()
+        .expect("Could not get cpuID"))
+        .expect("cpuid conversion overflow")
+}
+
+/// Offset of the glibc rseq area from the thread pointer.
+/// None when glibc did not register rseq (old glibc, or disabled with
+/// the glibc.pthread.rseq tunable)
+fn rseq_offset() -> Option<isize>
+{
+    static OFFSET: OnceLock<Option<isize>> = OnceLock::new();
+
+    *OFFSET.get_or_init(|| {
+        if !cfg!(any(target_arch = "x86_64", target_arch = "aarch64")) {
+            return None;
+        }
+
+        unsafe {
+            let size = nix::libc::dlsym(nix::libc::RTLD_DEFAULT, c"__rseq_size".as_ptr())
+                as *const u32;
+            let offset = nix::libc::dlsym(nix::libc::RTLD_DEFAULT, c"__rseq_offset".as_ptr())
+                as *const isize;
+
+            if size.is_null() || offset.is_null() || *size == 0 {
+                return None;
+            }
+            Some(*offset)
+        }
+    })
+}
+
+#[inline]
+fn thread_pointer() -> Option<*const u8>
+{
+    #[cfg(target_arch = "x86_64")]
+    {
+        let tp: *const u8;
+        unsafe { std::arch::asm!("mov {}, fs:0", out(reg) tp, options(nostack, readonly, preserves_flags)); }
+        Some(tp)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        let tp: *const u8;
+        unsafe { std::arch::asm!("mrs {}, tpidr_el0", out(reg) tp, options(nostack, nomem, preserves_flags)); }
+        Some(tp)
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    None
+}
+
+#[inline]
+fn rseq_core_id() -> Option<u16>
+{
+    let offset = rseq_offset()?;
+    let tp = thread_pointer()?;
+
+    // struct rseq { u32 cpu_id_start; u32 cpu_id; ... }
+    // cpu_id is negative while the area is not registered.
+    let cpu_id = unsafe {
+        std::ptr::read_volatile((tp.offset(offset) as *const i32).add(1))
+    };
+
+    u16::try_from(cpu_id).ok()
+}
+
+#[cfg(target_arch = "x86_64")]
+fn rdpid_supported() -> bool
+{
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+    *SUPPORTED.get_or_init(|| {
+        // cpuid is safe in recent rust versions
+        #[allow(unused_unsafe)]
+        let leaf = unsafe { std::arch::x86_64::__cpuid_count(7, 0) };
+        leaf.ecx & (1 << 22) != 0
+    })
+}
+
+#[cfg(target_arch = "x86_64")]
+fn rdtscp_supported() -> bool
+{
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+    *SUPPORTED.get_or_init(|| {
+        #[allow(unused_unsafe)]
+        let leaf = unsafe { std::arch::x86_64::__cpuid(0x8000_0001) };
+        leaf.edx & (1 << 27) != 0
+    })
+}
+
+/// Linux stores (node << 12 | cpu) in the TSC_AUX register that both
+/// rdpid and rdtscp return.
+#[cfg(target_arch = "x86_64")]
+#[inline]
+fn rdpid_core_id() -> Option<u16>
+{
+    let aux: u64 = if rdpid_supported() {
+        let aux: u64;
+        unsafe { std::arch::asm!("rdpid {}", out(reg) aux, options(nostack, nomem, preserves_flags)); }
+        aux
+    } else if rdtscp_supported() {
+        let mut aux: u32 = 0;
+        unsafe { std::arch::x86_64::__rdtscp(&mut aux); }
+        aux as u64
+    } else {
+        return None;
+    };
+
+    Some((aux & 0xfff) as u16)
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn rdpid_supported() -> bool { false }
+
+#[cfg(not(target_arch = "x86_64"))]
+fn rdtscp_supported() -> bool { false }
+
+#[cfg(not(target_arch = "x86_64"))]
+fn rdpid_core_id() -> Option<u16> { None }
+
+
+#[cfg(test)]
+mod profiler {
+
+    use super::*;
+
+    #[test]
+    fn core_modes_agree()
+    {
+        // Pin the thread to get a deterministic answer from all the
+        // modes.
+        let mut cpuset = nix::sched::CpuSet::new();
+        let core = syscall_core_id();
+        cpuset.set(core as usize).unwrap();
+        nix::sched::sched_setaffinity(nix::unistd::Pid::from_raw(0), &cpuset).unwrap();
+
+        for mode in CoreMode::ALL {
+            assert_eq!(mode.effective().core_id(), core, "Mode {} failed", mode);
+        }
+    }
+}
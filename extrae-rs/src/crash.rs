@@ -0,0 +1,332 @@
+//! Flush the traces when the program dies abnormally.
+//!
+//! When `flush_on_crash` is enabled we install a panic hook and
+//! handlers for SIGSEGV, SIGABRT and SIGTERM, so the trace can be
+//! merged later with the merger executable.
+//!
+//! The buffers are only flushed by their own threads. A crash asks
+//! the running threads to flush (see request_flush), every thread
+//! does it on its next event (see pending_flush) and the crash path
+//! waits a short grace period for them. The threads that emit nothing
+//! in that period keep their last events in memory.
+//!
+//! The panic hook runs in the panicking thread: it emits a "Crash"
+//! marker event with the thread and location and flushes that
+//! thread's buffer. The panics may be caught (catch_unwind, tokio
+//! tasks), so the other threads are asked to flush only when the
+//! panic is in the finalizer thread, because then the program is
+//! ending.
+//!
+//! The signal handlers are async-signal-safe: they wake a dedicated
+//! flusher thread through a pipe and wait for it with a timeout. The
+//! flusher records the signal in the trace metadata, asks the threads
+//! to flush and writes the .row and .pcf files. The interrupted thread
+//! cannot flush, its events since its last flush are lost. Then the
+//! signal goes to the previous handler; when the application handles
+//! a SIGTERM and goes on, our handler is installed again and the trace
+//! is finalized normally.
+//!
+//! The crash path only takes try locks, the crashing thread may hold
+//! any of them. The files that cannot be written are reported.
+
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, Ordering};
+
+use nix::libc;
+use nix::sys::signal;
+
+use crate::global_info::GlobalInfo;
+use crate::nameset::NameSet;
+use crate::thread_info::ThreadInfo;
+
+/// Signals that trigger the flush.
+const SIGNALS: [signal::Signal; 3] = [
+    signal::Signal::SIGSEGV,
+    signal::Signal::SIGABRT,
+    signal::Signal::SIGTERM,
+];
+
+/// Time the crash path waits for the running threads to flush.
+const FLUSH_GRACE: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// Time a signal handler waits for the flusher thread.
+const FLUSHER_TIMEOUT_MS: libc::c_int = 2000;
+
+/// Incremented to ask the running threads to flush their buffers.
+static FLUSH_REQUEST: AtomicU32 = AtomicU32::new(0);
+
+/// Threads that flushed since the last request.
+static FLUSH_ACKS: AtomicU32 = AtomicU32::new(0);
+
+/// Set while a crash is flushed. Only the first crash flushes.
+static FLUSHING: AtomicBool = AtomicBool::new(false);
+
+/// The signal and the trace id of the interrupted thread (0 when it
+/// is not traced), for the flusher thread.
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+static SIGNAL_THREAD: AtomicU32 = AtomicU32::new(0);
+
+/// Pipe ends used by the signal handlers to wake the flusher and to
+/// wait for it, and the process that owns the flusher thread (a
+/// forked child does not have it).
+static WAKE_FD: AtomicI32 = AtomicI32::new(-1);
+static DONE_FD: AtomicI32 = AtomicI32::new(-1);
+static FLUSHER_PID: AtomicI32 = AtomicI32::new(-1);
+
+/// Value of the panic marker when its name cannot be registered.
+static PANIC_VALUE: AtomicU32 = AtomicU32::new(0);
+
+/// The handlers installed before ours. We restore them before
+/// re-raising the signal.
+static PREVIOUS_ACTIONS: OnceLock<Vec<(signal::Signal, signal::SigAction)>> = OnceLock::new();
+
+/// Register the crash event and install the handlers.
+/// Returns the event id for the crash marker.
+pub(crate) fn install(name_set: &mut NameSet) -> u16
+{
+    let event_id = name_set.register_event_name_internal("Crash");
+    name_set.set_point_event(event_id);
+
+    PANIC_VALUE.store(
+        name_set.register_event_value_name("panic", Some("profiler"), None, event_id, None),
+        Ordering::Relaxed
+    );
+
+    install_panic_hook(event_id);
+
+    match spawn_flusher() {
+        Ok(()) => install_signal_handlers(),
+        Err(error) => eprintln!("Profiler failed to start the crash flusher: {}", error),
+    }
+
+    event_id
+}
+
+/// Check if a crash asked the threads to flush since the request
+/// `seen`. Returns the new request to remember.
+#[inline]
+pub(crate) fn pending_flush(seen: u32) -> Option<u32>
+{
+    let request = FLUSH_REQUEST.load(Ordering::Acquire);
+    (request != seen).then_some(request)
+}
+
+/// The current request, the new threads don't need to flush for the
+/// previous ones.
+pub(crate) fn current_flush() -> u32
+{
+    FLUSH_REQUEST.load(Ordering::Acquire)
+}
+
+/// Called by a thread after flushing for a request.
+pub(crate) fn ack_flush()
+{
+    FLUSH_ACKS.fetch_add(1, Ordering::Release);
+}
+
+/// Ask the running threads to flush their buffers and wait until
+/// `expected` threads did it, or the grace period ends. Returns the
+/// number of threads that flushed.
+pub(crate) fn request_flush(expected: u32) -> u32
+{
+    FLUSH_ACKS.store(0, Ordering::Release);
+    FLUSH_REQUEST.fetch_add(1, Ordering::AcqRel);
+
+    let start = std::time::Instant::now();
+    while FLUSH_ACKS.load(Ordering::Acquire) < expected && start.elapsed() < FLUSH_GRACE {
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+
+    FLUSH_ACKS.load(Ordering::Acquire)
+}
+
+/// Write a line to stdout without the stdout lock, that the crashing
+/// thread may hold.
+pub(crate) fn report(message: &str)
+{
+    write_line(libc::STDOUT_FILENO, message);
+}
+
+/// Like report, but to stderr.
+pub(crate) fn report_error(message: &str)
+{
+    write_line(libc::STDERR_FILENO, message);
+}
+
+fn write_line(fd: libc::c_int, message: &str)
+{
+    let line = format!("{}\n", message);
+    unsafe {
+        libc::write(fd, line.as_ptr() as *const libc::c_void, line.len());
+    }
+}
+
+fn install_panic_hook(event_id: u16)
+{
+    let previous = std::panic::take_hook();
+
+    std::panic::set_hook(Box::new(move |info| {
+        let thread = std::thread::current();
+        let name = format!("thread '{}' panicked", thread.name().unwrap_or("<unnamed>"));
+
+        let value = GlobalInfo::try_register_event_value_name(
+            &name,
+            info.location().map(|location| location.file()),
+            info.location().map(|location| location.line()),
+            event_id
+        ).unwrap_or_else(|| PANIC_VALUE.load(Ordering::Relaxed));
+
+        let flush_all = !FLUSHING.swap(true, Ordering::AcqRel);
+        GlobalInfo::flush_on_panic(value, flush_all);
+        if flush_all {
+            FLUSHING.store(false, Ordering::Release);
+        }
+
+        previous(info);
+    }));
+}
+
+/// Create a pipe with close on exec. Returns (read, write).
+fn pipe() -> std::io::Result<(libc::c_int, libc::c_int)>
+{
+    let mut fds = [0 as libc::c_int; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok((fds[0], fds[1]))
+}
+
+/// Start the thread that flushes the trace when a signal arrives. It
+/// does not emit events, so it does not count as a traced thread.
+fn spawn_flusher() -> std::io::Result<()>
+{
+    let (wake_read, wake_write) = pipe()?;
+    let (done_read, done_write) = pipe()?;
+
+    // The handlers drop the answers to old requests without blocking.
+    unsafe { libc::fcntl(done_read, libc::F_SETFL, libc::O_NONBLOCK) };
+
+    std::thread::Builder::new()
+        .name("extrae-flusher".to_string())
+        .spawn(move || {
+            let mut byte = 0u8;
+            loop {
+                let read = unsafe { libc::read(wake_read, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+                if read != 1 {
+                    if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return;
+                }
+
+                let name = signal::Signal::try_from(SIGNAL.load(Ordering::Acquire))
+                    .map_or("unknown", |sig| sig.as_str());
+                GlobalInfo::flush_on_signal(name, SIGNAL_THREAD.load(Ordering::Acquire));
+
+                unsafe { libc::write(done_write, &byte as *const u8 as *const libc::c_void, 1) };
+            }
+        })?;
+
+    WAKE_FD.store(wake_write, Ordering::Release);
+    DONE_FD.store(done_read, Ordering::Release);
+    FLUSHER_PID.store(nix::unistd::getpid().as_raw(), Ordering::Release);
+    Ok(())
+}
+
+fn crash_action() -> signal::SigAction
+{
+    signal::SigAction::new(
+        signal::SigHandler::Handler(crash_signal_handler),
+        // SA_ONSTACK to use the alternative stack set by the rust
+        // runtime on stack overflows.
+        signal::SaFlags::SA_ONSTACK,
+        signal::SigSet::empty()
+    )
+}
+
+fn install_signal_handlers()
+{
+    let action = crash_action();
+
+    let previous: Vec<(signal::Signal, signal::SigAction)> =
+        SIGNALS.iter()
+            .filter_map(|&sig| {
+                unsafe { signal::sigaction(sig, &action) }
+                    .map(|old| (sig, old))
+                    .map_err(|err| eprintln!("Profiler failed to install {} handler: {}", sig, err))
+                    .ok()
+            })
+            .collect();
+
+    PREVIOUS_ACTIONS.set(previous).expect("Crash handlers installed twice");
+}
+
+/// Wake the flusher thread and wait until it finishes or the timeout
+/// expires. Only async-signal-safe calls here.
+fn wake_flusher_and_wait()
+{
+    let (wake, done) = (WAKE_FD.load(Ordering::Acquire), DONE_FD.load(Ordering::Acquire));
+    let mut byte = 0u8;
+
+    unsafe {
+        // Drop the late answer of a request that timed out.
+        while libc::read(done, &mut byte as *mut u8 as *mut libc::c_void, 1) > 0 {}
+
+        if libc::write(wake, &byte as *const u8 as *const libc::c_void, 1) != 1 {
+            return;
+        }
+
+        let mut fd = libc::pollfd { fd: done, events: libc::POLLIN, revents: 0 };
+        libc::poll(&mut fd, 1, FLUSHER_TIMEOUT_MS);
+    }
+}
+
+extern "C" fn crash_signal_handler(signum: libc::c_int)
+{
+    // Only the first crash flushes, and never in a forked child
+    // because the flusher thread is in the parent.
+    if unsafe { libc::getpid() } == FLUSHER_PID.load(Ordering::Acquire)
+        && !FLUSHING.swap(true, Ordering::AcqRel) {
+        SIGNAL.store(signum, Ordering::Release);
+        SIGNAL_THREAD.store(ThreadInfo::current_trace_id(), Ordering::Release);
+        wake_flusher_and_wait();
+        FLUSHING.store(false, Ordering::Release);
+    }
+
+    let Ok(sig) = signal::Signal::try_from(signum) else {
+        return;
+    };
+
+    let previous = PREVIOUS_ACTIONS.get()
+        .and_then(|actions| actions.iter().find(|(s, _)| *s == sig))
+        .map(|(_, action)| *action)
+        .unwrap_or_else(|| signal::SigAction::new(
+            signal::SigHandler::SigDfl,
+            signal::SaFlags::empty(),
+            signal::SigSet::empty()
+        ));
+
+    unsafe {
+        let _ = signal::sigaction(sig, &previous);
+    }
+
+    // A fault happens again when we return and abort raises again, so
+    // the faults and aborts end with the previous handler.
+    if sig != signal::Signal::SIGTERM {
+        let _ = signal::raise(sig);
+        return;
+    }
+
+    // Deliver the SIGTERM now. The default action ends here; when the
+    // application handles it and we return, the program goes on and
+    // the trace is finalized normally.
+    let mut mask = signal::SigSet::empty();
+    mask.add(sig);
+    let _ = mask.thread_unblock();
+    let _ = signal::raise(sig);
+
+    unsafe {
+        let _ = signal::sigaction(sig, &crash_action());
+    }
+    GlobalInfo::crash_survived();
+}
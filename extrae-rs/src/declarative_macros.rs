@@ -59,6 +59,35 @@ macro_rules! instrument_function {
     };
 }
 
+/// Await a future instrumented with the Instrumented adapter. Used by
+/// #[extrae_profile] on async functions.
+#[macro_export]
+macro_rules! instrument_async {
+    ($name:literal, $value:literal, $awaiting:literal, $future:expr) => {{
+        #[cfg(feature = "profiling")]
+        let __future = {
+            static PROFILER_ONCE: std::sync::OnceLock<(u16, u16)> = std::sync::OnceLock::new();
+            let (id, awaiting_id) = *PROFILER_ONCE.get_or_init(|| {
+                let id = extrae_rs::GlobalInfo::register_event_name(
+                    $name, Some(file!()), Some(line!()), Some($value)
+                );
+                let awaiting_id = if $awaiting {
+                    extrae_rs::GlobalInfo::register_point_event_name(
+                        concat!($name, ":awaiting"), Some(file!()), Some(line!())
+                    )
+                } else {
+                    0
+                };
+                (id, awaiting_id)
+            });
+            extrae_rs::Instrumented::new($future, id, awaiting_id)
+        };
+        #[cfg(not(feature = "profiling"))]
+        let __future = $future;
+        __future.await
+    }};
+}
+
 #[macro_export]
 macro_rules! instrument_update {
     ($arg1:expr) => {
@@ -11,19 +11,10 @@ impl EventHeader {
     pub(crate)fn new() -> Self
     {
         Self {
-            time: u64::try_from(Self::get_nano_seconds())
-                .expect("Time conversion overflow"),
-            core: u16::try_from(nix::sched::sched_getcpu()
-                .expect("Could not get cpuID"))
-                .expect("cpuid conversion overflow"),
+            time: crate::clock::elapsed_ns(),
+            core: crate::cpuid::current_core_id(),
         }
     }
-
-    /// Get nano-seconds since the trace begins for a given timePoint
-    fn get_nano_seconds() -> u128 {
-        static START_TIMESTAMP: std::sync::OnceLock::<std::time::Instant> = std::sync::OnceLock::<std::time::Instant>::new();
-        START_TIMESTAMP.get_or_init(std::time::Instant::now).elapsed().as_nanos()
-    }
 }
 
 #[repr(C)]
@@ -58,6 +49,15 @@ impl EventEntry {
             info: EventInfo { id, value }
         }
     }
+
+    /// Create an entry like the emission path does. Only for the
+    /// benchmarks.
+    #[cfg(feature = "bench")]
+    #[doc(hidden)]
+    pub fn bench_new(id: u16, value: u32) -> Self
+    {
+        Self::new(id, value)
+    }
 }
 
 // Needed to sort in the heap
@@ -73,6 +73,95 @@ impl Ord for EventEntry {
     }
 }
 
+/// Event ids at the top of the u16 range are reserved for the Paraver
+/// caller types used by Extrae, which don't fit in the u16 ids. Every
+/// kind of caller has MAX_CALLER_LEVELS ids, one per stack level.
+pub(crate) const CALLER_EVENT_BASE: u16 = 0xFF00;
+
+/// Maximum stack depth recorded in the caller events.
+pub(crate) const MAX_CALLER_LEVELS: u16 = 32;
+
+/// Families of caller events. The values of these events are indices
+/// in the address table (see symbols.rs) that the merger translates
+/// to functions or file:line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum CallerKind {
+    /// Function of a sampled stack frame.
+    SampledFunction,
+    /// File and line of a sampled stack frame.
+    SampledLine,
+    /// Function of the caller of an instrumented region.
+    Function,
+    /// File and line of the caller of an instrumented region.
+    Line,
+}
+
+impl CallerKind {
+    pub(crate) const ALL: [CallerKind; 4] = [
+        CallerKind::SampledFunction,
+        CallerKind::SampledLine,
+        CallerKind::Function,
+        CallerKind::Line,
+    ];
+
+    /// Paraver type for level 0, the level is added to it.
+    fn paraver_base(self) -> u32
+    {
+        match self {
+            CallerKind::SampledFunction => 30000000,
+            CallerKind::SampledLine => 30000100,
+            CallerKind::Function => 70000000,
+            CallerKind::Line => 80000000,
+        }
+    }
+
+    pub(crate) fn label(self) -> &'static str
+    {
+        match self {
+            CallerKind::SampledFunction => "Sampled caller",
+            CallerKind::SampledLine => "Sampled caller line",
+            CallerKind::Function => "Caller",
+            CallerKind::Line => "Caller line",
+        }
+    }
+
+    /// The values are file:line instead of functions.
+    pub(crate) fn is_line(self) -> bool
+    {
+        matches!(self, CallerKind::SampledLine | CallerKind::Line)
+    }
+
+    /// Event id for a stack level, from 1 (the innermost frame) to
+    /// MAX_CALLER_LEVELS.
+    pub(crate) fn event_id(self, level: u16) -> u16
+    {
+        assert!((1..=MAX_CALLER_LEVELS).contains(&level), "Invalid caller level {}", level);
+        CALLER_EVENT_BASE + self as u16 * MAX_CALLER_LEVELS + level - 1
+    }
+
+    /// Kind and level of a caller event id.
+    pub(crate) fn from_event_id(id: u16) -> Option<(CallerKind, u16)>
+    {
+        let offset = id.checked_sub(CALLER_EVENT_BASE)?;
+        let kind = *Self::ALL.get((offset / MAX_CALLER_LEVELS) as usize)?;
+        Some((kind, offset % MAX_CALLER_LEVELS + 1))
+    }
+
+    pub(crate) fn paraver_type(self, level: u16) -> u32
+    {
+        self.paraver_base() + level as u32
+    }
+}
+
+/// Paraver event type for an event id.
+pub(crate) fn paraver_type(id: u16) -> u32
+{
+    match CallerKind::from_event_id(id) {
+        Some((kind, level)) => kind.paraver_type(level),
+        None => id as u32,
+    }
+}
+
 impl std::fmt::Display for EventEntry {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "core:{} time:{} id:{} value:{}",
@@ -101,7 +190,26 @@ mod profiler {
 
         assert!(event_entry1 < event_entry2);
 
-        let event_clone = event_entry1.clone();
+        let event_clone = event_entry1;
         assert_eq!(event_entry1, event_clone);
     }
+
+    #[test]
+    fn caller_event_ids()
+    {
+        assert_eq!(paraver_type(12), 12);
+
+        let id = CallerKind::Line.event_id(3);
+        assert_eq!(CallerKind::from_event_id(id), Some((CallerKind::Line, 3)));
+        assert_eq!(paraver_type(id), 80000003);
+
+        let id = CallerKind::SampledFunction.event_id(1);
+        assert_eq!(id, CALLER_EVENT_BASE);
+        assert_eq!(paraver_type(id), 30000001);
+
+        let last = CallerKind::Line.event_id(MAX_CALLER_LEVELS);
+        assert_eq!(paraver_type(last), 80000000 + MAX_CALLER_LEVELS as u32);
+        assert_eq!(CallerKind::from_event_id(last + 1), None);
+        assert_eq!(CallerKind::from_event_id(CALLER_EVENT_BASE - 1), None);
+    }
 }
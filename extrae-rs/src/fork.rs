@@ -0,0 +1,48 @@
+//! Fork support.
+//!
+//! After a fork the child is a copy of the parent with a single
+//! thread: it inherits the global info, the thread local buffer of the
+//! forking thread (with its open Trace_*.bin file) and the perf
+//! counters of the parent. Without any action the child events would
+//! end up in the parent's files.
+//!
+//! We register a pthread_atfork child handler that gives the child a
+//! fresh buffer set in a subdirectory of the parent trace directory
+//! (`fork_<pid>`) and re-creates the counters of the forking thread.
+//! The handler takes no profiler lock: the parent pid and the counters
+//! state are kept in the global info and written to the trace metadata
+//! at finalize. The
+//! child trace is finalized when the forking thread exits and it can
+//! be merged as any other trace.
+//!
+//! The locks held by other threads at the fork are never released in
+//! the child: when the fork happens while another thread registers a
+//! new event name, the child deadlocks on its next registration.
+
+use std::sync::Once;
+
+use nix::libc;
+
+use crate::global_info::GlobalInfo;
+use crate::thread_info::ThreadInfo;
+
+/// Register the fork handlers. Only the first call has effect.
+pub(crate) fn install()
+{
+    static INSTALLED: Once = Once::new();
+
+    INSTALLED.call_once(|| {
+        let ret = unsafe { libc::pthread_atfork(None, None, Some(child_handler)) };
+        if ret != 0 {
+            println!("Profiler failed to register the fork handlers: {}",
+                std::io::Error::from_raw_os_error(ret));
+        }
+    });
+}
+
+extern "C" fn child_handler()
+{
+    if GlobalInfo::reset_after_fork() {
+        ThreadInfo::reset_after_fork();
+    }
+}
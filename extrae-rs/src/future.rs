@@ -0,0 +1,136 @@
+//! Instrumentation for futures and streams. A Guard in an async
+//! function lives across the `.await` points, so the region starts in
+//! one thread and ends in another one. Instead, the Instrumented
+//! adapter emits the region around every poll, in the thread that
+//! polls the future.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+
+use crate::subscriber::SubscriberContainer;
+
+/// A future that emits its event with value 1 when a poll starts and 0
+/// when it returns.
+///
+/// The next poll may run in another thread, so the time awaiting is
+/// not a region. Instead, with an awaiting event (id != 0) the polls
+/// that return Pending also emit that event with value 1, a point
+/// event marking where the future started to wait.
+pub struct Instrumented<F> {
+    inner: F,
+    id: u16,
+    awaiting_id: u16,
+}
+
+impl<F> Instrumented<F> {
+    pub fn new(inner: F, id: u16, awaiting_id: u16) -> Self
+    {
+        Self { inner, id, awaiting_id }
+    }
+
+    fn enter(&mut self)
+    {
+        crate::ThreadInfo::emplace_event_and_counters(self.id, 1);
+    }
+
+    fn exit(&mut self, pending: bool)
+    {
+        let awaiting_id = if pending { self.awaiting_id } else { 0 };
+        crate::ThreadInfo::emplace_events_and_counters(&[(self.id, 0), (awaiting_id, 1)]);
+    }
+}
+
+impl<F: Future> Future for Instrumented<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output>
+    {
+        // The inner future is never moved
+        let this = unsafe { self.get_unchecked_mut() };
+
+        this.enter();
+        let poll = unsafe { Pin::new_unchecked(&mut this.inner) }.poll(cx);
+        this.exit(poll.is_pending());
+
+        poll
+    }
+}
+
+impl<S: Stream> Stream for Instrumented<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>>
+    {
+        // The inner stream is never moved
+        let this = unsafe { self.get_unchecked_mut() };
+
+        this.enter();
+        let poll = unsafe { Pin::new_unchecked(&mut this.inner) }.poll_next(cx);
+        this.exit(poll.is_pending());
+
+        poll
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>)
+    {
+        self.inner.size_hint()
+    }
+}
+
+/// Instrument futures without wrapping them in a function, like the
+/// ones built with combinators or from other crates:
+///
+/// ```ignore
+/// let body = client.get(url).send().extrae_instrument("request").await?;
+/// ```
+///
+/// Every poll is a region of the event with that name.
+pub trait ExtraeFutureExt: Future + Sized {
+    /// The event is registered the first time the name is used, with
+    /// the location of the caller.
+    #[track_caller]
+    fn extrae_instrument(self, name: &'static str) -> Instrumented<Self>
+    {
+        instrument(self, name)
+    }
+}
+
+impl<F: Future> ExtraeFutureExt for F {}
+
+/// Like ExtraeFutureExt, for streams:
+///
+/// ```ignore
+/// let mut lines = reader.lines().extrae_instrument("lines");
+/// ```
+pub trait ExtraeStreamExt: Stream + Sized {
+    /// The event is registered the first time the name is used, with
+    /// the location of the caller.
+    #[track_caller]
+    fn extrae_instrument(self, name: &'static str) -> Instrumented<Self>
+    {
+        instrument(self, name)
+    }
+}
+
+impl<S: Stream> ExtraeStreamExt for S {}
+
+/// The futures and the streams share the event of a name.
+#[track_caller]
+fn instrument<T>(inner: T, name: &'static str) -> Instrumented<T>
+{
+    static NAMES: OnceLock<SubscriberContainer<&'static str, u16>> = OnceLock::new();
+
+    let location = std::panic::Location::caller();
+    let id = NAMES.get_or_init(SubscriberContainer::new).get_or_insert_with(
+        &name,
+        || crate::GlobalInfo::register_event_name(
+            name, Some(location.file()), Some(location.line()), None
+        )
+    );
+
+    Instrumented::new(inner, id, 0)
+}
@@ -1,37 +1,461 @@
+use std::sync::Mutex;
+
 use serde::Deserialize;
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct GlobalConfig {
     pub(crate) automerge: bool,
     pub(crate) counters: Vec<String>, // Example array
+    pub(crate) counters_per_group: usize,
+    pub(crate) counter_scaling: bool,
+    pub(crate) counter_mode: crate::CounterMode,
+    pub(crate) perf_refuse: crate::perf::PerfRefuse,
+    pub(crate) sampling: String,
+    pub(crate) sampling_period: u64,
+    pub(crate) sampling_depth: u16,
+    pub(crate) caller_depth: u16,
     pub(crate) suffix: String,
+    pub(crate) core_mode: crate::CoreMode,
+    pub(crate) codec: crate::Codec,
+    pub(crate) merge_threads: usize,
+    pub(crate) flush_on_crash: bool,
+    pub(crate) output_dir: String,
+    pub(crate) trace_dir: String,
+    pub(crate) existing_dir: crate::ExistingDir,
+    pub(crate) latest_link: bool,
+    pub(crate) buffer_size: usize,
+    pub(crate) clock: crate::Clock,
+    pub(crate) filters: Vec<String>,
+    pub(crate) span_fields: Vec<String>,
+    pub(crate) tokio_tasks: bool,
+    pub(crate) runtime_metrics_period: u64,
+}
+
+/// Error in the profiler configuration.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// A value in the builder, the file or the environment is invalid.
+    Parse(config::ConfigError),
+    /// The builder was applied after the profiler was initialized
+    /// (the first event was emitted).
+    AlreadyInitialized,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Parse(error) => write!(f, "Invalid profiler configuration: {}", error),
+            ConfigError::AlreadyInitialized => write!(f, "The profiler is already initialized"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Parse(error) => Some(error),
+            ConfigError::AlreadyInitialized => None,
+        }
+    }
+}
+
+impl From<config::ConfigError> for ConfigError {
+    fn from(error: config::ConfigError) -> Self {
+        ConfigError::Parse(error)
+    }
+}
+
+impl ConfigError {
+    /// Error for an invalid value of a key.
+    fn invalid_value(key: &str, error: impl std::fmt::Display) -> Self
+    {
+        ConfigError::Parse(config::ConfigError::At {
+            error: Box::new(config::ConfigError::Message(error.to_string())),
+            origin: None,
+            key: Some(key.to_string()),
+        })
+    }
+
+    /// The top level key with the invalid value, when it is known.
+    fn key(&self) -> Option<&str>
+    {
+        match self {
+            ConfigError::Parse(config::ConfigError::Type { key: Some(key), .. })
+                | ConfigError::Parse(config::ConfigError::At { key: Some(key), .. }) => {
+                    key.split(['.', '[']).next()
+                },
+            _ => None,
+        }
+    }
+}
+
+/// Programmatic profiler configuration.
+///
+/// The values set here override the defaults, but the `extrae.toml`
+/// file and the `EXTRAE_*` environment variables still have
+/// precedence over them:
+///
+/// defaults < ConfigBuilder < extrae.toml < EXTRAE_* environment
+///
+/// The builder must be applied before the first event is emitted:
+///
+/// ```
+/// extrae_rs::ConfigBuilder::new()
+///     .counters(["cycles", "instructions"])
+///     .output_dir("traces")
+///     .codec(extrae_rs::Codec::Lz4)
+///     .apply()
+///     .expect("Invalid profiler configuration");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ConfigBuilder {
+    values: Vec<(&'static str, config::Value)>,
+}
+
+/// The builder applied with ConfigBuilder::apply
+static BUILDER: Mutex<Option<ConfigBuilder>> = Mutex::new(None);
+
+impl ConfigBuilder {
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    fn set<V: Into<config::Value>>(mut self, key: &'static str, value: V) -> Self
+    {
+        self.values.push((key, value.into()));
+        self
+    }
+
+    /// Performance counters to read on every event.
+    pub fn counters<I, S>(self, counters: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let counters: Vec<String> = counters.into_iter().map(Into::into).collect();
+        self.set("counters", counters)
+    }
+
+    /// Maximum number of counters read together in a group. When the
+    /// counters do not fit in the PMU the kernel multiplexes the
+    /// groups. 0 puts all the counters in the same group.
+    pub fn counters_per_group(self, counters: usize) -> Self
+    {
+        self.set("counters_per_group", counters as u64)
+    }
+
+    /// Scale the multiplexed counter values to estimate the value for
+    /// the whole execution time.
+    pub fn counter_scaling(self, enabled: bool) -> Self
+    {
+        self.set("counter_scaling", enabled)
+    }
+
+    /// Attach the accumulated counter values to the events, or the
+    /// increment since the previous event in the thread.
+    pub fn counter_mode(self, mode: crate::CounterMode) -> Self
+    {
+        self.set("counter_mode", mode.to_string())
+    }
+
+    /// Event that triggers the call stack samples, like `cpu-clock`
+    /// or `cycles`. An empty name disables the sampling.
+    pub fn sampling(self, event: &str) -> Self
+    {
+        self.set("sampling", event)
+    }
+
+    /// Number of events between two samples; nanoseconds for the
+    /// `cpu-clock` and `task-clock` events.
+    pub fn sampling_period(self, period: u64) -> Self
+    {
+        self.set("sampling_period", period)
+    }
+
+    /// Levels of the call stack recorded in every sample (1 to 32).
+    pub fn sampling_depth(self, levels: u16) -> Self
+    {
+        self.set("sampling_depth", levels as u64)
+    }
+
+    /// Levels of the call stack recorded as caller events when an
+    /// instrumented region starts (up to 32). 0 disables them.
+    pub fn caller_depth(self, levels: u16) -> Self
+    {
+        self.set("caller_depth", levels as u64)
+    }
+
+    /// Root directory for the traces.
+    pub fn output_dir<P: AsRef<std::path::Path>>(self, dir: P) -> Self
+    {
+        self.set("output_dir", dir.as_ref().to_string_lossy().into_owned())
+    }
+
+    /// Template for the trace directory name.
+    pub fn trace_dir(self, template: &str) -> Self
+    {
+        self.set("trace_dir", template)
+    }
+
+    /// Tag for the {tag} placeholder in the trace directory name.
+    pub fn tag(self, tag: &str) -> Self
+    {
+        self.set("suffix", tag)
+    }
+
+    pub fn existing_dir(self, policy: crate::ExistingDir) -> Self
+    {
+        self.set("existing_dir", policy.to_string())
+    }
+
+    pub fn latest_link(self, enabled: bool) -> Self
+    {
+        self.set("latest_link", enabled)
+    }
+
+    /// Merge the .prv file at the end of the execution.
+    pub fn automerge(self, enabled: bool) -> Self
+    {
+        self.set("automerge", enabled)
+    }
+
+    pub fn merge_threads(self, threads: usize) -> Self
+    {
+        self.set("merge_threads", threads as u64)
+    }
+
+    /// Format of the Trace_*.bin files.
+    pub fn codec(self, codec: crate::Codec) -> Self
+    {
+        self.set("codec", codec.to_string())
+    }
+
+    /// Memory per thread (in bytes) to store events before writing
+    /// them to the file.
+    pub fn buffer_size(self, bytes: usize) -> Self
+    {
+        self.set("buffer_size", bytes as u64)
+    }
+
+    /// Clock used for the event timestamps.
+    pub fn clock(self, clock: crate::Clock) -> Self
+    {
+        self.set("clock", clock.to_string())
+    }
+
+    pub fn core_mode(self, mode: crate::CoreMode) -> Self
+    {
+        self.set("core_mode", mode.to_string())
+    }
+
+    /// Event names that won't be emitted. A trailing `*` matches any
+    /// name with that prefix.
+    pub fn filters<I, S>(self, filters: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let filters: Vec<String> = filters.into_iter().map(Into::into).collect();
+        self.set("filters", filters)
+    }
+
+    /// Span fields recorded as events by the ExtraeSubscriber. A
+    /// trailing `*` matches any field with that prefix, `*` records
+    /// all of them.
+    pub fn span_fields<I, S>(self, fields: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let fields: Vec<String> = fields.into_iter().map(Into::into).collect();
+        self.set("span_fields", fields)
+    }
+
+    /// Emit the tokio tasks polled by every thread (task id and spawn
+    /// location) instead of the runtime.spawn spans.
+    pub fn tokio_tasks(self, enabled: bool) -> Self
+    {
+        self.set("tokio_tasks", enabled)
+    }
+
+    /// Milliseconds between two samples of the RuntimeSampler.
+    pub fn runtime_metrics_period(self, period: u64) -> Self
+    {
+        self.set("runtime_metrics_period", period)
+    }
+
+    pub fn flush_on_crash(self, enabled: bool) -> Self
+    {
+        self.set("flush_on_crash", enabled)
+    }
+
+    /// Validate the configuration and use it when the profiler is
+    /// initialized.
+    pub fn apply(self) -> Result<(), ConfigError>
+    {
+        if crate::GlobalInfo::is_initialized() {
+            return Err(ConfigError::AlreadyInitialized);
+        }
+
+        GlobalConfig::build(Some(&self), true)?;
+
+        *BUILDER.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(self);
+        Ok(())
+    }
 }
 
 impl GlobalConfig {
+
+    /// Get the configuration from the defaults, the applied
+    /// ConfigBuilder, the extrae.toml file and the environment.
+    ///
+    /// The errors are reported and the invalid values are replaced by
+    /// the builder or default ones; the other file and environment
+    /// values are kept. When the invalid key is not known (like a
+    /// malformed file) the file and the environment are ignored.
     pub(crate) fn new() -> GlobalConfig
     {
-        config::Config::builder()
-            .set_default("counters", Vec::<String>::new())
-            .expect("Failed to set default counters")
-            .set_default("automerge", true)
-            .expect("Failed to set default automerge")
-            .set_default("suffix", "")
-            .expect("Failed to set default tracedir suffix")
-            .add_source(config::File::with_name("extrae").required(false))
-            .add_source(config::Environment::with_prefix("EXTRAE")
-                .ignore_empty(true)
-                .try_parsing(true)
-                .with_list_parse_key("counters")
-                .ignore_empty(true)
-                .list_separator(","))
-            .build().unwrap()
-            .try_deserialize::<GlobalConfig>()
-            .unwrap()
+        let builder = BUILDER.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut ignored = Vec::<String>::new();
+
+        loop {
+            let error = match Self::build_ignoring(builder.as_ref(), true, &ignored) {
+                Ok(config) => return config,
+                Err(error) => error,
+            };
+
+            match error.key() {
+                Some(key) if !ignored.iter().any(|ignored| ignored == key) => {
+                    eprintln!("Profiler {}; ignoring the {} value", error, key);
+                    ignored.push(key.to_string());
+                },
+                _ => {
+                    eprintln!("Profiler {}; ignoring extrae.toml and EXTRAE_* variables", error);
+                    return Self::build(builder.as_ref(), false)
+                        .or_else(|_| Self::build(None, false))
+                        .expect("Invalid default configuration");
+                }
+            }
+        }
     }
+
+    fn build(builder: Option<&ConfigBuilder>, external: bool) -> Result<GlobalConfig, ConfigError>
+    {
+        Self::build_ignoring(builder, external, &[])
+    }
+
+    /// Build the configuration with the file and environment values
+    /// of the ignored keys replaced by the builder or default ones.
+    fn build_ignoring(
+        builder: Option<&ConfigBuilder>,
+        external: bool,
+        ignored: &[String]
+    ) -> Result<GlobalConfig, ConfigError> {
+        let mut config_builder = Self::sources(builder, external)?;
+
+        if external && !ignored.is_empty() {
+            let internal = Self::sources(builder, false)?.build()?;
+            for key in ignored {
+                config_builder = config_builder.set_override(key.as_str(), internal.get::<config::Value>(key)?)?;
+            }
+        }
+
+        let config = config_builder
+            .build()?
+            .try_deserialize::<GlobalConfig>()?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check the values that the deserialization cannot.
+    fn validate(&self) -> Result<(), ConfigError>
+    {
+        crate::tracedir::TemplateValues::check(&self.trace_dir)
+            .map_err(|error| ConfigError::invalid_value("trace_dir", error))
+    }
+
+    /// The defaults, the builder values and, when external is true,
+    /// the extrae.toml file and the environment.
+    fn sources(
+        builder: Option<&ConfigBuilder>,
+        external: bool
+    ) -> Result<config::ConfigBuilder<config::builder::DefaultState>, ConfigError> {
+        let mut config_builder = config::Config::builder()
+            .set_default("counters", Vec::<String>::new())?
+            .set_default("counters_per_group", 0)?
+            .set_default("counter_scaling", true)?
+            .set_default("counter_mode", "cumulative")?
+            .set_default("perf_refuse", "none")?
+            .set_default("sampling", "")?
+            .set_default("sampling_period", 1_000_000)?
+            .set_default("sampling_depth", 8)?
+            .set_default("caller_depth", 0)?
+            .set_default("automerge", true)?
+            .set_default("suffix", "")?
+            .set_default("core_mode", "syscall")?
+            .set_default("codec", "raw")?
+            .set_default("merge_threads", 1)?
+            .set_default("flush_on_crash", false)?
+            .set_default("output_dir", ".")?
+            .set_default("trace_dir", "TRACEDIR_{tag}")?
+            .set_default("existing_dir", "fail")?
+            .set_default("latest_link", false)?
+            .set_default("buffer_size", 1024 * 1024)?
+            .set_default("clock", "monotonic")?
+            .set_default("filters", Vec::<String>::new())?
+            .set_default("span_fields", Vec::<String>::new())?
+            .set_default("tokio_tasks", false)?
+            .set_default("runtime_metrics_period", 10)?;
+
+        // The builder values replace the defaults
+        for (key, value) in builder.map(|builder| builder.values.as_slice()).unwrap_or_default() {
+            config_builder = config_builder.set_default(*key, value.clone())?;
+        }
+
+        if external {
+            config_builder = config_builder
+                .add_source(config::File::with_name("extrae").required(false))
+                .add_source(config::Environment::with_prefix("EXTRAE")
+                    .ignore_empty(true)
+                    .try_parsing(true)
+                    .with_list_parse_key("counters")
+                    .with_list_parse_key("filters")
+                    .with_list_parse_key("span_fields")
+                    .ignore_empty(true)
+                    .list_separator(","));
+        }
+
+        Ok(config_builder)
+    }
+
+    /// Check if an event name is disabled by the filters.
+    pub(crate) fn is_filtered(&self, name: &str) -> bool
+    {
+        matches_any(&self.filters, name)
+    }
+
+    /// Check if a span field is recorded by the subscriber.
+    pub(crate) fn records_span_field(&self, name: &str) -> bool
+    {
+        matches_any(&self.span_fields, name)
+    }
+}
+
+/// Check a name against a list of names, a trailing `*` matches any
+/// name with that prefix.
+fn matches_any(patterns: &[String], name: &str) -> bool
+{
+    patterns.iter().any(|pattern| match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    })
 }
 
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod global_config {
 
     use super::*;
@@ -75,12 +499,13 @@ mod global_config {
         std::env::remove_var("EXTRAE_counters");
 
         // Test default constructor
-        let config_default = GlobalConfig::new();
+        let config_default = GlobalConfig::build(None, true).unwrap();
         assert_eq!(config_default.counters, Vec::<String>::new());
+        assert!(!config_default.latest_link);
 
         // From environment
         std::env::set_var("EXTRAE_counters","111,222");
-        let config_env = GlobalConfig::new();
+        let config_env = GlobalConfig::build(None, true).unwrap();
         assert_eq!(config_env.counters, vec!["111", "222"]);
         std::env::remove_var("EXTRAE_counters");
 
@@ -89,14 +514,68 @@ mod global_config {
         writeln!(temp_file.file, "counters = [\"333\", \"444\"]").expect("Failed to write");
         temp_file.file.flush().unwrap();
 
-        let config_file = GlobalConfig::new();
+        let config_file = GlobalConfig::build(None, true).unwrap();
         assert_eq!(config_file.counters, vec!["333", "444"]);
 
         // From file and environment
         std::env::set_var("EXTRAE_counters","111,222");
-        let config_file2 = GlobalConfig::new();
+        let config_file2 = GlobalConfig::build(None, true).unwrap();
         assert_eq!(config_file2.counters, vec!["111", "222"]);
         std::env::remove_var("EXTRAE_counters");
+
+        // Builder values have lower precedence than the file and the
+        // environment
+        let builder = ConfigBuilder::new()
+            .counters(["555"])
+            .codec(crate::Codec::Lz4)
+            .buffer_size(4096)
+            .filters(["skip", "tokio::*"])
+            .span_fields(["size", "request_*"]);
+
+        let config_builder = GlobalConfig::build(Some(&builder), true).unwrap();
+        assert_eq!(config_builder.counters, vec!["333", "444"]);
+        assert_eq!(config_builder.codec, crate::Codec::Lz4);
+        assert_eq!(config_builder.buffer_size, 4096);
+
+        assert!(config_builder.is_filtered("skip"));
+        assert!(config_builder.is_filtered("tokio::task"));
+        assert!(!config_builder.is_filtered("skip2"));
+
+        assert!(config_builder.records_span_field("size"));
+        assert!(config_builder.records_span_field("request_id"));
+        assert!(!config_builder.records_span_field("user"));
+
+        std::env::set_var("EXTRAE_codec","compact");
+        let config_builder_env = GlobalConfig::build(Some(&builder), true).unwrap();
+        assert_eq!(config_builder_env.codec, crate::Codec::Compact);
+
+        // Invalid values are reported as errors
+        std::env::set_var("EXTRAE_codec","invalid");
+        assert!(matches!(GlobalConfig::build(None, true), Err(ConfigError::Parse(_))));
+
+        // new only ignores the invalid values
+        std::env::set_var("EXTRAE_trace_dir","TRACEDIR_{unknown}");
+        std::env::set_var("EXTRAE_buffer_size","8192");
+        let error = GlobalConfig::build(None, true).unwrap_err();
+        assert!(error.key().is_some());
+
+        let config_lenient = GlobalConfig::new();
+        assert_eq!(config_lenient.codec, crate::Codec::Raw);
+        assert_eq!(config_lenient.trace_dir, "TRACEDIR_{tag}");
+        assert_eq!(config_lenient.buffer_size, 8192);
+        assert_eq!(config_lenient.counters, vec!["333", "444"]);
+
+        std::env::remove_var("EXTRAE_codec");
+        let error = GlobalConfig::build(None, true).unwrap_err();
+        assert_eq!(error.key(), Some("trace_dir"));
+
+        std::env::remove_var("EXTRAE_trace_dir");
+        std::env::remove_var("EXTRAE_buffer_size");
+
+        // The builder templates are checked too
+        let builder = ConfigBuilder::new().trace_dir("{program}_{");
+        let error = GlobalConfig::build(Some(&builder), true).unwrap_err();
+        assert_eq!(error.key(), Some("trace_dir"));
     }
 }
 
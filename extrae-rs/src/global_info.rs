@@ -13,12 +13,39 @@ pub struct GlobalInfo {
 
     threads_running: atomic::AtomicU32,
 
+    // Set after a crash flush (see crash.rs)
+    crashed: atomic::AtomicBool,
+
+    // Set when some thread could not open all the counters
+    counters_degraded: atomic::AtomicBool,
+
+    // The thread that calls finalize when it ends. This is the main
+    // thread, or the forking thread in a forked child.
+    finalizer_tid: std::thread::ThreadId,
+
+    // The parent process in a forked child. It goes to the pcf
+    // metadata at finalize, the child cannot take the names lock
+    // when it is created (see fork.rs).
+    parent_pid: Option<nix::unistd::Pid>,
+
     pub(crate) config: GlobalConfig,
 
     pub thread_event_id: u16,
 
+    // Crash marker event ID (when flush_on_crash is enabled)
+    crash_event_id: Option<u16>,
+
     // Hardware events ID
     pub events_info: Vec<(String, u16)>,
+
+    // Options for the counter groups of every thread
+    pub(crate) perf_options: crate::perf::PerfOptions,
+
+    // Options for the stack sampler of every thread
+    pub(crate) sampling_options: crate::sampling::SamplingOptions,
+
+    // Stack levels recorded when a region starts (0 = disabled)
+    pub(crate) caller_depth: u16,
 }
 
 impl GlobalInfo {
@@ -40,70 +67,197 @@ impl GlobalInfo {
 
         let config = GlobalConfig::new();
 
+        let clock = config.clock.set_global();
+        if clock != config.clock {
+            println!("Profiler clock {} requested after the first event, using {}", config.clock, clock);
+        }
+
+        let core_mode = config.core_mode.set_global();
+        if core_mode != config.core_mode {
+            println!("Profiler core mode {} not supported, using {}", config.core_mode, core_mode);
+        }
+
+        if config.codec.effective() != config.codec {
+            println!("Profiler codec {} not enabled, using {}", config.codec, config.codec.effective());
+        }
+
         let start_system_time =
             std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .expect("Time went backwards");
 
-        // TODO: Convert the time to something friendly
-        let trace_dir = if ! config.suffix.is_empty() {
-            format!("TRACEDIR_{}", config.suffix)
-        } else {
-            format!("TRACEDIR_{}", start_system_time.as_millis())
-        };
+        let template_values
+            = crate::tracedir::TemplateValues::current(&start_system_time, &config.suffix);
+
+        let trace_dir = template_values
+            .expand(&config.trace_dir)
+            .expect("The trace_dir template is checked by GlobalConfig");
 
-        let trace_directory_path = std::path::PathBuf::from(trace_dir);
-        std::fs::create_dir(&trace_directory_path)
-            .unwrap_or_else(|_| {
-                panic!("Failed to create trace directory: {}",
-                    trace_directory_path.as_os_str()
-                    .to_str()
-                    .unwrap())
-            });
+        let output_dir = std::path::PathBuf::from(&config.output_dir);
+
+        let trace_directory_path = crate::tracedir::create_trace_dir(
+            &output_dir,
+            &trace_dir,
+            config.existing_dir
+        ).unwrap_or_else(|error| {
+            panic!("Failed to create trace directory: {}: {}",
+                output_dir.join(&trace_dir).display(),
+                error)
+        });
+
+        if config.latest_link {
+            if let Err(error) = crate::tracedir::update_latest_link(&output_dir, &trace_directory_path) {
+                println!("Profiler failed to update the latest link: {}", error);
+            }
+        }
 
         let mut name_set = crate::nameset::NameSet::new();
         let buffer_set = crate::bufferset::BufferSet::new(
             start_system_time,
-            trace_directory_path
+            trace_directory_path,
+            config.codec,
+            config.buffer_size
         );
 
         let thread_event_id = name_set.register_event_name_internal("ThreadRuning");
 
+        let crash_event_id = config.flush_on_crash
+            .then(|| crate::crash::install(&mut name_set));
+
         // Register all the possible supported events to preserve the ids.
-        let all_events_info: BTreeMap<&str, u16> =
+        let all_events_info: BTreeMap<String, u16> =
             SomeEvent::EVENTS_LIST
                 .iter()
                 .map(|(name, _)| {
-                    let eid  = name_set.register_event_name_internal(&name);
-                    (*name, eid)
+                    let pcf_name = crate::perf::counter_pcf_name(name);
+                    let eid  = name_set.register_counter_name(&pcf_name);
+                    (pcf_name, eid)
                 })
                 .collect();
 
 
-        // For events info we take all the input names and then
-        // iterate filtering only the valid names and registering
-        // them.
+        // For events info we validate the input names and register
+        // only the usable ones; the problems are reported.
         let events_info: Vec<(String, u16)> =
-            config
-                .counters
-                .iter()
-                .filter(|name| ! matches!(SomeEvent::event_from_str(name), SomeEvent::None))
+            crate::perf::validate_counters(&config.counters, config.perf_refuse)
+                .into_iter()
                 .map(|name| {
-                    let eid  = all_events_info.get(name.as_str()).unwrap();
-                    (name.clone(), *eid)
+                    // Cache, raw, PMU events and the counters with
+                    // modifiers get their ids now
+                    let pcf_name = crate::perf::counter_pcf_name(&name);
+                    let eid = match all_events_info.get(pcf_name.as_str()) {
+                        Some(eid) => *eid,
+                        None => name_set.register_counter_name(&pcf_name),
+                    };
+                    (name, eid)
                 })
                 .collect();
 
         println!("Profiler enabled counters: {:?}", events_info);
 
+        // Without permissions the profiler continues with the usable
+        // counters; the trace records what is missing.
+        if !config.counters.is_empty() {
+            // The equivalent counters are not missing
+            let dropped: Vec<&str> = config.counters.iter()
+                .filter(|name| {
+                    let pcf_name = crate::perf::counter_pcf_name(name);
+                    !events_info.iter().any(|(usable, _)| crate::perf::counter_pcf_name(usable) == pcf_name)
+                })
+                .map(String::as_str)
+                .collect();
+
+            let state = if events_info.is_empty() {
+                "disabled"
+            } else if dropped.is_empty() {
+                "ok"
+            } else {
+                "degraded"
+            };
+
+            name_set.set_metadata("counters_state", state);
+            if !dropped.is_empty() {
+                name_set.set_metadata("counters_dropped", &dropped.join(","));
+                eprintln!("Profiler counters {}, missing: {}", state, dropped.join(","));
+            }
+        }
+
+        // The analysis tools need the mode to interpret the values
+        name_set.set_metadata("counter_mode", &config.counter_mode.to_string());
+
+        let perf_options = crate::perf::PerfOptions {
+            counters_per_group: config.counters_per_group,
+            scaling: config.counter_scaling,
+            mode: config.counter_mode,
+            refuse: config.perf_refuse,
+            running_ratio_id: if events_info.is_empty() {
+                0
+            } else {
+                name_set.register_counter_name("Counters running ratio (per mille)")
+            },
+        };
+
+        let sampling_options = Self::validate_sampling(&config, &name_set);
+
+        let caller_depth = config.caller_depth.min(crate::event::MAX_CALLER_LEVELS);
+        if caller_depth > 0 {
+            name_set.set_metadata("caller_depth", &caller_depth.to_string());
+        }
+
+        crate::fork::install();
+
         Self {
             buffer_set,
             name_set,
             threads_running: atomic::AtomicU32::new(0),
+            crashed: atomic::AtomicBool::new(false),
+            counters_degraded: atomic::AtomicBool::new(false),
+            finalizer_tid: std::thread::current().id(),
+            parent_pid: None,
             thread_event_id,
+            crash_event_id,
             config,
-            events_info
+            events_info,
+            perf_options,
+            sampling_options,
+            caller_depth
+        }
+    }
+
+    /// Check that the sampling event can be opened. When it can't,
+    /// the problem is reported and the sampling is disabled.
+    fn validate_sampling(
+        config: &GlobalConfig,
+        name_set: &crate::nameset::NameSet
+    ) -> crate::sampling::SamplingOptions {
+
+        let mut options = crate::sampling::SamplingOptions {
+            event: config.sampling.clone(),
+            period: config.sampling_period,
+            depth: config.sampling_depth.clamp(1, crate::event::MAX_CALLER_LEVELS),
+            refuse: config.perf_refuse,
+        };
+
+        if !options.enabled() {
+            return options;
+        }
+
+        match crate::sampling::StackSampler::new(&options) {
+            Ok(_) => {
+                name_set.set_metadata("sampling", &options.event);
+                name_set.set_metadata("sampling_period", &options.period.to_string());
+                name_set.set_metadata("sampling_depth", &options.depth.to_string());
+                println!("Profiler sampling: {} every {} (depth {})",
+                    options.event, options.period, options.depth);
+            },
+            Err(error) => {
+                println!("Profiler sampling disabled, {}: {}", options.event, error);
+                name_set.set_metadata("sampling", "disabled");
+                options.event.clear();
+            },
         }
+
+        options
     }
 
     /// Create a new buffer for a thread
@@ -124,11 +278,19 @@ impl GlobalInfo {
     /// actions.
     fn finalize_buffer(&mut self, buffer: &buffer::Buffer)
     {
-        self.buffer_set.save_buffer_id(&buffer);
+        self.buffer_set.save_buffer_id(buffer);
         self.threads_running.fetch_sub(1, atomic::Ordering::Relaxed);
 
+        // After a crash the main thread may exit with other threads
+        // still running. Only update the files flushed by the crash
+        // handler.
+        if buffer.tid() == self.finalizer_tid && self.crashed.load(atomic::Ordering::Relaxed) {
+            self.crash_finalize();
+            return;
+        }
+
         // Call finalize if this is the main thread.
-        if buffer.name() == "main" {
+        if buffer.tid() == self.finalizer_tid {
             assert_eq!(self.threads_running.load(atomic::Ordering::Relaxed), 0);
             self.finalize();
         }
@@ -155,22 +317,135 @@ impl GlobalInfo {
 
         let output_path = self.buffer_set.trace_directory_path.as_path();
 
+        // In a forked child the directory is created on demand.
+        std::fs::create_dir_all(output_path).expect("Error creating trace directory");
+
         self.buffer_set
             .create_row(output_path)
             .expect("Error creatiiing ROW file");
+        for (key, value) in self.late_metadata() {
+            self.name_set.set_metadata(key, &value);
+        }
         self.name_set
             .create_pcf(output_path)
             .expect("Error creating PCF file");
+        crate::symbols::write_symbols(output_path)
+            .expect("Error creating SYM file");
+
+        // The pcf gets the caller names also when the trace is not
+        // merged now
+        let callers = crate::symbols::CallerTables::load(output_path)
+            .expect("Error resolving the callers");
+        if let Some(callers) = &callers {
+            callers.write_pcf(output_path, &self.caller_levels())
+                .expect("Error writing the callers to the PCF file");
+        }
 
         if self.config.automerge {
-            Merger::new(output_path)      // path to read from
-                .create_prv(output_path)  // path to write to
+            Merger::new(output_path, self.config.merge_threads, false) // path to read from
+                .map(|merger| match callers {
+                    Some(callers) => merger.with_callers(callers),
+                    None => merger,
+                })
+                .and_then(|mut merger| merger.create_prv(output_path))  // path to write to
                 .expect("Error creating PRV file");
         }
 
         println!("# Profiler TraceDir: {}", output_path.to_str().unwrap());
     }
 
+    /// The deepest level of every kind of caller event that the
+    /// configuration can emit.
+    fn caller_levels(&self) -> BTreeMap<crate::event::CallerKind, u16>
+    {
+        use crate::event::CallerKind;
+
+        let sampling_depth = if self.sampling_options.enabled() { self.sampling_options.depth } else { 0 };
+
+        [
+            (CallerKind::SampledFunction, sampling_depth),
+            (CallerKind::SampledLine, sampling_depth),
+            (CallerKind::Function, self.caller_depth),
+            (CallerKind::Line, self.caller_depth),
+        ].into_iter()
+            .filter(|(_, depth)| *depth > 0)
+            .collect()
+    }
+
+    /// The metadata known by the threads and the fork handler, that
+    /// don't take the names lock. It is set when the pcf is written.
+    fn late_metadata(&self) -> Vec<(&'static str, String)>
+    {
+        let mut metadata = Vec::new();
+
+        if self.counters_degraded.load(atomic::Ordering::Relaxed) {
+            metadata.push(("counters_state", "degraded".to_string()));
+        }
+        if let Some(parent_pid) = self.parent_pid {
+            metadata.push(("parent_pid", parent_pid.to_string()));
+        }
+
+        metadata
+    }
+
+    /// Reset the state in a forked child (see fork.rs).
+    ///
+    /// The child gets a new buffer set writing in a subdirectory of
+    /// the parent trace. The buffers of the parent threads are
+    /// forgotten, they don't exist in the child.
+    fn reset_in_child(&mut self)
+    {
+        let pid = nix::unistd::getpid();
+        let parent_pid = nix::unistd::getppid();
+
+        let trace_directory_path =
+            self.buffer_set.trace_directory_path.join(format!("fork_{}", pid));
+
+        // The directory is created on the first flush, so children that
+        // only call exec don't leave empty directories.
+        self.buffer_set = crate::bufferset::BufferSet::new(
+            self.buffer_set.start_system_time,
+            trace_directory_path,
+            self.config.codec,
+            self.config.buffer_size
+        );
+
+        self.threads_running.store(0, atomic::Ordering::Relaxed);
+        self.crashed.store(false, atomic::Ordering::Relaxed);
+        self.counters_degraded.store(false, atomic::Ordering::Relaxed);
+        self.finalizer_tid = std::thread::current().id();
+        self.parent_pid = Some(parent_pid);
+    }
+
+    /// Partial finalization when the process is going to die.
+    ///
+    /// Write the .row and .pcf files, the buffers are flushed by their
+    /// own threads (see crash.rs). The .prv is not created here; use
+    /// the merger executable on the trace directory. Only try locks
+    /// are taken, the problems are reported.
+    fn crash_finalize(&self)
+    {
+        let output_path = self.buffer_set.trace_directory_path.as_path();
+
+        for (key, value) in self.late_metadata() {
+            self.name_set.try_set_metadata(key, &value);
+        }
+
+        let written = std::fs::create_dir_all(output_path)
+            .and_then(|_| self.buffer_set.write_row(output_path))
+            .and_then(|_| self.name_set.try_create_pcf(output_path))
+            .and_then(|_| crate::symbols::write_symbols(output_path));
+
+        match written {
+            Ok(()) => crate::crash::report(&format!(
+                "# Profiler flushed on crash, TraceDir: {}", output_path.display()
+            )),
+            Err(error) => crate::crash::report_error(&format!(
+                "Profiler failed to write trace on crash: {}", error
+            )),
+        }
+    }
+
 }
 
 /// This is the variable to store the global information.
@@ -198,7 +473,7 @@ impl GlobalInfo {
     pub(crate) fn as_ref() -> &'static GlobalInfo
     {
         unsafe {
-            if let None = INFO {
+            if INFO.is_none() {
                 INFO = Some(GlobalInfo::new());
             }
 
@@ -206,13 +481,39 @@ impl GlobalInfo {
         }
     }
 
+    /// Check if the profiler was already initialized.
+    pub(crate) fn is_initialized() -> bool
+    {
+        unsafe { INFO.is_some() }
+    }
+
+    /// Some counters could not be opened in a thread, the trace
+    /// metadata records it.
+    pub(crate) fn notify_counters_degraded()
+    {
+        Self::as_ref().counters_degraded.store(true, atomic::Ordering::Relaxed);
+    }
+
+    /// Check if an event name is disabled by the filters option.
+    pub(crate) fn is_filtered(event_name: &str) -> bool
+    {
+        Self::as_ref().config.is_filtered(event_name)
+    }
+
+    /// Check if a span field is recorded by the subscriber, with the
+    /// span_fields option.
+    pub(crate) fn records_span_field(field_name: &str) -> bool
+    {
+        Self::as_ref().config.records_span_field(field_name)
+    }
+
     /// Get a buffer for this thread.
     /// The buffer may be created now or maybe recovered from a previous save.
     /// This requires mutable access to the variable.
     pub(crate) fn get_thread_buffer(tid: std::thread::ThreadId, name: &str) -> crate::buffer::Buffer
     {
         unsafe {
-            INFO.get_or_insert_with(|| GlobalInfo::new())
+            INFO.get_or_insert_with(GlobalInfo::new)
         }.init_buffer(tid, name)
     }
 
@@ -230,11 +531,86 @@ impl GlobalInfo {
         }.finalize_buffer(buffer);
     }
 
+    /// Emit the crash marker event with `value` in the current thread
+    /// and flush its buffer. Called from the panic hook.
+    ///
+    /// The panic may be caught and the program continue, so the other
+    /// threads are only asked to flush (with `flush_all`) when the
+    /// panic is in the finalizer thread (see crash.rs).
+    pub(crate) fn flush_on_panic(value: u32, flush_all: bool)
+    {
+        let Some(info) = (unsafe { INFO.as_ref() }) else {
+            return;
+        };
+
+        let flushed = info.crash_event_id
+            .is_some_and(|event_id| crate::ThreadInfo::try_emplace_event_and_flush(event_id, value));
+
+        if !flush_all || std::thread::current().id() != info.finalizer_tid {
+            return;
+        }
+
+        info.crashed.store(true, atomic::Ordering::Relaxed);
+
+        let running = info.threads_running.load(atomic::Ordering::Relaxed);
+        crate::crash::request_flush(running.saturating_sub(flushed as u32));
+        info.crash_finalize();
+    }
+
+    /// Flush the trace after a signal. Called from the flusher thread
+    /// (see crash.rs), `thread` is the trace id of the interrupted
+    /// thread (0 when it is not traced), that cannot flush its buffer.
+    /// The signal and the thread are recorded in the metadata.
+    /// This does nothing if the profiler was not initialized.
+    pub(crate) fn flush_on_signal(signal: &str, thread: u32)
+    {
+        let Some(info) = (unsafe { INFO.as_ref() }) else {
+            return;
+        };
+
+        info.crashed.store(true, atomic::Ordering::Relaxed);
+
+        info.name_set.try_set_metadata("signal", signal);
+        if thread != 0 {
+            info.name_set.try_set_metadata("signal_thread", &thread.to_string());
+        }
+
+        let running = info.threads_running.load(atomic::Ordering::Relaxed);
+        crate::crash::request_flush(running.saturating_sub((thread != 0) as u32));
+        info.crash_finalize();
+    }
+
+    /// The program goes on after a signal handled by the application
+    /// (see crash.rs), so the trace is finalized normally. Called from
+    /// the signal handler.
+    pub(crate) fn crash_survived()
+    {
+        if let Some(info) = unsafe { INFO.as_ref() } {
+            info.crashed.store(false, atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// Called in the child process after a fork. Returns false when
+    /// the profiler was not initialized in the parent.
+    pub(crate) fn reset_after_fork() -> bool
+    {
+        match unsafe { INFO.as_mut() } {
+            Some(info) => {
+                info.reset_in_child();
+                true
+            },
+            None => false
+        }
+    }
+
     /// Internal api function to register a new event name.
     /// The arguments are as described ny their names.
     /// Remember that the events are identified by their id, not by
     /// their names; so, multiple ids can repeat names and they will be
     /// difficult to identify in the final trace.
+    ///
+    /// The names disabled by the filters option are not registered
+    /// and get the id 0, the events with id 0 are never emitted.
     #[inline]
     pub fn register_event_name(
         event_name: &str,
@@ -242,11 +618,39 @@ impl GlobalInfo {
         line: Option<u32>,
         event: Option<u16>
     ) -> u16 {
-        unsafe {
-            INFO.get_or_insert_with(|| GlobalInfo::new())
-                .name_set
-                .register_event_name(event_name, file_name, line, event)
+        let info = unsafe { INFO.get_or_insert_with(GlobalInfo::new) };
+
+        if info.config.is_filtered(event_name) {
+            return 0;
         }
+
+        info.name_set.register_event_name(event_name, file_name, line, event)
+    }
+
+    /// Register an event whose values are instants and not regions,
+    /// like the log records or the metrics. The recovery merger does
+    /// not close them at the truncation point.
+    pub fn register_point_event_name(
+        event_name: &str,
+        file_name: Option<&str>,
+        line: Option<u32>
+    ) -> u16 {
+        let event_id = Self::register_event_name(event_name, file_name, line, None);
+        Self::as_ref().name_set.set_point_event(event_id);
+        event_id
+    }
+
+    /// Like register_event_value_name, but it gives up when the names
+    /// lock is taken. Used from the panic hook.
+    pub(crate) fn try_register_event_value_name(
+        event_name: &str,
+        file_name: Option<&str>,
+        line: Option<u32>,
+        event: u16
+    ) -> Option<u32> {
+        unsafe { INFO.as_ref() }?
+            .name_set
+            .try_register_event_value_name(event_name, file_name, line, event)
     }
 
     /// The paraver format can assign names also to the values of the
@@ -258,8 +662,13 @@ impl GlobalInfo {
         event: u16,
         value: Option<u32>
     ) -> u32 {
+        // Filtered event
+        if event == 0 {
+            return 0;
+        }
+
         unsafe {
-            INFO.get_or_insert_with(|| GlobalInfo::new())
+            INFO.get_or_insert_with(GlobalInfo::new)
                 .name_set
                 .register_event_value_name(event_name, file_name, line, event, value)
         }
@@ -275,7 +684,7 @@ impl GlobalInfo {
         value: Option<u32>
     ) -> Option<crate::nameset::NameInfo> {
         unsafe {
-            INFO.get_or_insert_with(|| GlobalInfo::new())
+            INFO.get_or_insert_with(GlobalInfo::new)
                 .name_set
                 .get_event_value_info(event, value)
         }
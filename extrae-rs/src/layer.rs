@@ -0,0 +1,80 @@
+use tracing::{span, Event, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::subscriber::{SpanEvents, SpanInstance};
+
+/// A tracing_subscriber Layer with the same events as the
+/// ExtraeSubscriber. It composes with other layers in a registry, and
+/// the spans and events filtered by tracing never reach it:
+///
+/// ```ignore
+/// tracing_subscriber::registry()
+///     .with(ExtraeLayer::new())
+///     .with(tracing_subscriber::fmt::layer())
+///     .init();
+/// ```
+///
+/// The span instance (event id and value) is stored in the span
+/// extensions of the registry.
+pub struct ExtraeLayer {
+    mapping: SpanEvents,
+}
+
+impl ExtraeLayer {
+    pub fn new() -> Self {
+        Self { mapping: SpanEvents::new() }
+    }
+
+    fn instance<S>(id: &span::Id, ctx: &Context<'_, S>) -> Option<SpanInstance>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        ctx.span(id)?.extensions().get::<SpanInstance>().copied()
+    }
+}
+
+impl Default for ExtraeLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> tracing_subscriber::Layer<S> for ExtraeLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        let instance = self.mapping.new_instance(attrs);
+        span.extensions_mut().insert(instance);
+
+        self.mapping.emit_fields(attrs.metadata(), &span::Record::new(attrs.values()));
+    }
+
+    /// Emit the fields recorded with Span::record
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(instance) = Self::instance(id, &ctx) {
+            self.mapping.emit_fields(instance.metadata, values);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        self.mapping.emit_event(event);
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(instance) = Self::instance(id, &ctx) {
+            self.mapping.enter(&instance);
+        }
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(instance) = Self::instance(id, &ctx) {
+            self.mapping.exit(&instance);
+        }
+    }
+}
@@ -1,17 +1,36 @@
 mod event;
 pub use event::EventEntry;
 
+mod cpuid;
+pub use cpuid::CoreMode;
+
+mod clock;
+pub use clock::Clock;
+
 mod bufferinfo;
 pub use bufferinfo::BufferInfo;
 
 mod buffer;
 
+mod codec;
+pub use codec::Codec;
+
+mod tracedir;
+pub use tracedir::ExistingDir;
+
 mod nameset;
 mod bufferset;
 
 mod perf;
+mod symbols;
+mod sampling;
+pub use perf::{CounterError, CounterMode, all_counters, check_counter, known_counters, perf_event_paranoid};
 
 mod global_config;
+pub use global_config::{ConfigBuilder, ConfigError};
+
+mod crash;
+mod fork;
 
 mod global_info;
 pub use global_info::GlobalInfo;
@@ -22,12 +41,26 @@ pub use thread_info::ThreadInfo;
 mod profiler;
 pub use profiler::Guard;
 
+mod future;
+pub use future::{ExtraeFutureExt, ExtraeStreamExt, Instrumented};
+
 mod parser;
-pub(crate) use parser::Merger;
+pub use parser::Merger;
 
 mod subscriber;
 pub use subscriber::ExtraeSubscriber;
 
+mod layer;
+pub use layer::ExtraeLayer;
+
+mod runtime_metrics;
+pub use runtime_metrics::RuntimeSampler;
+
+#[cfg(feature = "log")]
+mod logger;
+#[cfg(feature = "log")]
+pub use logger::ExtraeLogger;
+
 mod declarative_macros;
 
 // Re-export the macro. This is essential for users of your library
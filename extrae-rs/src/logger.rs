@@ -0,0 +1,150 @@
+//! Integration with the log crate, for the dependencies that use it
+//! instead of tracing.
+
+use crate::subscriber::SubscriberContainer;
+
+/// A log::Log implementation that emits every record as a point event.
+/// Every call site gets a value of the "log" event, named with its
+/// message, or with its file:line when the message has arguments (the
+/// first message would not describe the others). The records without
+/// location are keyed by their target and message, and the ones with
+/// arguments share a single "dynamic" value, so the values don't grow
+/// with every message. The level is emitted as the "log_level" event
+/// (1 error to 5 trace).
+///
+/// The records can be forwarded to another logger to keep the usual
+/// output:
+///
+/// ```ignore
+/// ExtraeLogger::new()
+///     .max_level(log::LevelFilter::Info)
+///     .forward_to(Box::new(env_logger::Logger::from_default_env()))
+///     .init()
+///     .expect("A logger was already set");
+/// ```
+pub struct ExtraeLogger {
+    log_event_id: u16,
+    level_event_id: u16,
+    /// Value of every call site.
+    call_sites: SubscriberContainer<String, u32>,
+    max_level: log::LevelFilter,
+    forward: Option<Box<dyn log::Log>>,
+}
+
+impl ExtraeLogger {
+    pub fn new() -> Self
+    {
+        let log_event_id = crate::GlobalInfo::register_point_event_name("log", None, None);
+        let level_event_id = crate::GlobalInfo::register_point_event_name("log_level", None, None);
+
+        for level in log::Level::iter() {
+            crate::GlobalInfo::register_event_value_name(
+                level.as_str(), None, None, level_event_id, Some(level as u32)
+            );
+        }
+
+        // The trace is finalized when the main thread ends
+        crate::ThreadInfo::with(|_| {});
+
+        Self {
+            log_event_id,
+            level_event_id,
+            call_sites: SubscriberContainer::new(),
+            max_level: log::LevelFilter::Trace,
+            forward: None,
+        }
+    }
+
+    /// Ignore the records less important than this level.
+    pub fn max_level(mut self, level: log::LevelFilter) -> Self
+    {
+        self.max_level = level;
+        self
+    }
+
+    /// Send the records to this logger too. It applies its own
+    /// filters.
+    pub fn forward_to(mut self, logger: Box<dyn log::Log>) -> Self
+    {
+        self.forward = Some(logger);
+        self
+    }
+
+    /// Set this as the global logger.
+    pub fn init(self) -> Result<(), log::SetLoggerError>
+    {
+        let max_level = self.max_level;
+
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(max_level);
+        Ok(())
+    }
+
+    /// Get a value for the call site of a record.
+    fn call_site_value(&self, record: &log::Record<'_>) -> u32
+    {
+        // An empty name is replaced by file:line when registered
+        let (key, name) = match (record.file(), record.line()) {
+            (Some(file), Some(line)) => (
+                format!("{}:{}", file, line),
+                record.args().as_str().unwrap_or_default().to_string()
+            ),
+            _ => {
+                let message = match record.args().as_str() {
+                    Some(message) => format!("{} {}", record.target(), message),
+                    None => "dynamic".to_string(),
+                };
+                (message.clone(), message)
+            },
+        };
+
+        self.call_sites.get_or_insert_with(
+            &key,
+            || crate::GlobalInfo::register_event_value_name(
+                &name,
+                record.file(),
+                record.line(),
+                self.log_event_id,
+                None
+            )
+        )
+    }
+}
+
+impl Default for ExtraeLogger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl log::Log for ExtraeLogger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool
+    {
+        metadata.level() <= self.max_level
+            || self.forward.as_ref().is_some_and(|logger| logger.enabled(metadata))
+    }
+
+    fn log(&self, record: &log::Record<'_>)
+    {
+        if record.level() <= self.max_level && self.log_event_id != 0 {
+            let value = self.call_site_value(record);
+            crate::ThreadInfo::emplace_events_and_counters(&[
+                (self.level_event_id, record.level() as u32),
+                (self.log_event_id, value)
+            ]);
+        }
+
+        if let Some(logger) = &self.forward {
+            if logger.enabled(record.metadata()) {
+                logger.log(record);
+            }
+        }
+    }
+
+    fn flush(&self)
+    {
+        if let Some(logger) = &self.forward {
+            logger.flush();
+        }
+    }
+}
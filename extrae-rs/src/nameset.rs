@@ -3,8 +3,8 @@
 use std::io::Write;
 use std::str::FromStr;
 use std::sync::atomic;
-use std::sync::{Arc, RwLock};
-use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock, RwLockReadGuard, TryLockError};
+use std::collections::{BTreeMap, BTreeSet};
 use std::collections::btree_map::Entry;
 
 /// A struct representing information about a name, including its file
@@ -19,10 +19,10 @@ pub(crate) struct NameInfo {
 }
 
 impl NameInfo {
-    fn new(name: &String, path: Option<&str>, line: Option<u32>) -> Self
+    fn new(name: &str, path: Option<&str>, line: Option<u32>) -> Self
     {
         Self {
-            name: name.clone(),
+            name: name.to_string(),
             path: std::path::PathBuf::from_str(path.unwrap_or_default()).expect("Error converting path"),
             line: line.unwrap_or_default()
         }
@@ -42,7 +42,7 @@ struct NameEntry {
 }
 
 impl NameEntry {
-    fn new(name: &String, path: Option<&str>, line: Option<u32>) -> Self
+    fn new(name: &str, path: Option<&str>, line: Option<u32>) -> Self
     {
         Self {
             info: NameInfo::new(name, path, line),
@@ -53,25 +53,35 @@ impl NameEntry {
 
 pub(crate) struct NameSet {
     counter: atomic::AtomicU16,
+    counters_counter: atomic::AtomicU16,
     names_event_map: Arc<RwLock<BTreeMap<u16, NameEntry>>>,
+    metadata: RwLock<BTreeMap<String, String>>,
+    point_events: RwLock<BTreeSet<u16>>,
 }
 
 impl NameSet {
 
     const  MAX_USER_EVENT: u16 = u16::MAX / 2;
-    const  MAX_EVENT: u16 = u16::MAX;
+
+    /// The performance counters use their own range, so the merger can
+    /// distinguish them from the regions without the pcf.
+    const  COUNTER_EVENT_BASE: u16 = 0xF000;
 
     pub fn new() -> Self
     {
         Self {
             counter: atomic::AtomicU16::new(Self::MAX_USER_EVENT),
-            names_event_map:  Arc::new(RwLock::new(BTreeMap::new()))
+            counters_counter: atomic::AtomicU16::new(Self::COUNTER_EVENT_BASE),
+            names_event_map:  Arc::new(RwLock::new(BTreeMap::new())),
+            metadata: RwLock::new(BTreeMap::new()),
+            point_events: RwLock::new(BTreeSet::new())
         }
     }
 
     /// Register a new event with event_name and event_id
-    /// When event_id is not specified the function generated a new event_it
+    /// When event_id is not specified (or zero) the function generated a new event_it
     /// The generated id is in the internal range (above the user events range)
+    /// The id zero is reserved for the filtered events.
     pub fn register_event_name(
         &mut self,
         event_name: &str,
@@ -91,14 +101,14 @@ impl NameSet {
         // Is the provided id is zero we use the internal events counter.
         let mut event_ref: u16 =
             match event_id {
-                Some(evt) => {
+                Some(evt) if evt != 0 => {
                     assert!(evt < Self::MAX_USER_EVENT,
                         "Event value must be < {}", Self::MAX_USER_EVENT);
                     evt
                 },
-                None => {
+                _ => {
                     let last = self.counter.fetch_add(1, atomic::Ordering::Relaxed);
-                    assert!(last < Self::MAX_EVENT,
+                    assert!(last + 1 < Self::COUNTER_EVENT_BASE,
                         "Internal counter event value reached the limit");
                     last + 1
                 }
@@ -134,6 +144,27 @@ impl NameSet {
         self.register_event_name(event_name, Some("profiler"), None, None)
     }
 
+    /// Register a performance counter name.
+    /// The ids are taken from the counters range (see is_counter_event)
+    pub fn register_counter_name(&mut self, counter_name: &str) -> u16
+    {
+        let last = self.counters_counter.fetch_add(1, atomic::Ordering::Relaxed);
+        assert!(last < crate::event::CALLER_EVENT_BASE, "Counter event value reached the limit");
+
+        self.names_event_map
+            .write()
+            .expect("Failed to get name_set lock")
+            .insert(last, NameEntry::new(counter_name, Some("profiler"), None));
+
+        last
+    }
+
+    /// Check if an event id corresponds to a performance counter.
+    pub fn is_counter_event(event_id: u16) -> bool
+    {
+        (Self::COUNTER_EVENT_BASE..crate::event::CALLER_EVENT_BASE).contains(&event_id)
+    }
+
     pub fn register_event_value_name(
         &mut self,
         value_name: &str,
@@ -204,14 +235,114 @@ impl NameSet {
             }
     }
 
+    /// Set a key/value with information about the trace. The metadata
+    /// is written as comments at the beginning of the pcf file.
+    pub fn set_metadata(&self, key: &str, value: &str)
+    {
+        self.metadata
+            .write()
+            .expect("Failed to get metadata lock")
+            .insert(key.to_string(), value.to_string());
+    }
+
+    /// Like set_metadata, but it gives up when the lock is taken. Used
+    /// from the crash paths. Returns if the value was set.
+    pub(crate) fn try_set_metadata(&self, key: &str, value: &str) -> bool
+    {
+        self.metadata
+            .try_write()
+            .map(|mut metadata| metadata.insert(key.to_string(), value.to_string()))
+            .is_ok()
+    }
+
+    /// Like register_event_value_name with the next free value, but
+    /// it gives up (returns None) when the lock is taken or the event
+    /// does not exist. Used from the panic hook, where panicking
+    /// again would abort.
+    pub(crate) fn try_register_event_value_name(
+        &self,
+        value_name: &str,
+        file_name: Option<&str>,
+        line: Option<u32>,
+        event: u16
+    ) -> Option<u32> {
+        let mut maplock = self.names_event_map.try_write().ok()?;
+        let values = &mut maplock.get_mut(&event)?.names_values_map;
+
+        let value = values.keys().next_back().map_or(1, |last| last + 1);
+        values.insert(value, NameInfo::new(value_name, file_name, line));
+        Some(value)
+    }
+
+    /// Mark an event as a point event: every value is an instant and
+    /// not the start of a region, so nothing closes it with a 0. The
+    /// list goes to the pcf metadata, the recovery merger reads it to
+    /// not close these events at the truncation point.
+    pub fn set_point_event(&self, event_id: u16)
+    {
+        if event_id != 0 {
+            self.point_events
+                .write()
+                .expect("Failed to get point_events lock")
+                .insert(event_id);
+        }
+    }
+
     pub fn create_pcf(&self, trace_dir: &std::path::Path) -> std::io::Result<()>
     {
+        self.write_pcf(trace_dir, true)
+    }
+
+    /// Like create_pcf, but it fails when some lock is taken instead
+    /// of waiting. Used from the crash paths.
+    pub(crate) fn try_create_pcf(&self, trace_dir: &std::path::Path) -> std::io::Result<()>
+    {
+        self.write_pcf(trace_dir, false)
+    }
+
+    /// Take a read lock, or only try when not `blocking`.
+    fn read_lock<'a, T>(
+        lock: &'a RwLock<T>,
+        blocking: bool,
+        what: &str
+    ) -> std::io::Result<RwLockReadGuard<'a, T>> {
+        if blocking {
+            return Ok(lock.read().unwrap_or_else(|_| panic!("Failed to get {} lock", what)));
+        }
+
+        lock.try_read().or_else(|error| match error {
+            TryLockError::Poisoned(poisoned) => Ok(poisoned.into_inner()),
+            TryLockError::WouldBlock => Err(std::io::Error::new(
+                std::io::ErrorKind::WouldBlock,
+                format!("the {} lock is taken", what)
+            )),
+        })
+    }
+
+    fn write_pcf(&self, trace_dir: &std::path::Path, blocking: bool) -> std::io::Result<()>
+    {
+        let mut metadata = Self::read_lock(&self.metadata, blocking, "metadata")?.clone();
+
+        let point_events = Self::read_lock(&self.point_events, blocking, "point_events")?;
+        if !point_events.is_empty() {
+            let ids: Vec<String> = point_events.iter().map(u16::to_string).collect();
+            metadata.insert("point_events".to_string(), ids.join(","));
+        }
+
+        let mapread = Self::read_lock(&self.names_event_map, blocking, "name_set")?;
+
         let file = std::fs::File::create(trace_dir.join("Trace.pcf"))?;
         let mut writer = std::io::BufWriter::new(file);
 
-        let mapread = self.names_event_map.read().expect("Failed to get name_set lock");
+        if !metadata.is_empty() {
+            for (key, value) in metadata.iter() {
+                writeln!(writer, "#META {}={}", key, value)?;
+            }
+            writeln!(writer)?;
+        }
 
-        for (key, &ref name_entry) in mapread.iter() {
+
+        for (key, name_entry) in mapread.iter() {
             writeln!(writer, "# {}:{}", name_entry.info.path.to_str().unwrap(), name_entry.info.line)?;
             writeln!(writer, "EVENT_TYPE")?;
             writeln!(writer, "0 {} {}", key, name_entry.info.name)?;
@@ -219,12 +350,12 @@ impl NameSet {
             if !name_entry.names_values_map.is_empty() {
                 writeln!(writer, "VALUES")?;
 
-                for (key, &ref value_entry) in name_entry.names_values_map.iter() {
+                for (key, value_entry) in name_entry.names_values_map.iter() {
                     writeln!(writer, "{} {}:{}", key, name_entry.info.name, value_entry.name)?;
                 }
             }
 
-            writeln!(writer, "")?;
+            writeln!(writer)?;
         }
 
         Ok(())
@@ -233,6 +364,7 @@ impl NameSet {
 
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod nameset{
 
     use super::*;
@@ -329,4 +461,39 @@ mod nameset{
         name_set.create_pcf(std::path::Path::new("/tmp")).unwrap();
     }
 
+    #[test]
+    fn register_counter_names()
+    {
+        let mut name_set = NameSet::new();
+
+        let internal = name_set.register_event_name_internal("Internal");
+        let counter1 = name_set.register_counter_name("cycles");
+        let counter2 = name_set.register_counter_name("instructions");
+
+        assert!(!NameSet::is_counter_event(1));
+        assert!(!NameSet::is_counter_event(internal));
+        assert!(NameSet::is_counter_event(counter1));
+        assert_eq!(counter2, counter1 + 1);
+
+        assert!(name_set.get_event_value_info(counter2, None).is_some_and(|info| info.name == "instructions"));
+    }
+
+    #[test]
+    fn pcf_metadata()
+    {
+        let mut name_set = NameSet::new();
+        name_set.register_event_name_internal("Internal");
+        name_set.set_metadata("parent_pid", "1");
+        name_set.set_metadata("parent_pid", "2");
+        name_set.set_point_event(5);
+        name_set.set_point_event(3);
+        name_set.set_point_event(0);
+
+        let dir = std::path::Path::new("/tmp/pcf_metadata");
+        std::fs::create_dir_all(dir).unwrap();
+        name_set.create_pcf(dir).unwrap();
+
+        let pcf = std::fs::read_to_string(dir.join("Trace.pcf")).unwrap();
+        assert!(pcf.starts_with("#META parent_pid=2\n#META point_events=3,5\n"), "{}", pcf);
+    }
 }
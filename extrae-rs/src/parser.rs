@@ -1,38 +1,113 @@
 #![allow(dead_code)]
 
-use std::io::{Read, Write};
+use std::io::{Read, Seek, Write};
 use std::fs::File;
 use std::iter::Iterator;
 
 use chrono::TimeZone;
 
-use crate::{bufferinfo,event};
+use crate::{bufferinfo,codec,event};
 
 // Iterator for the array inside the file.
+// The events are decoded one chunk at the time.
+//
+// In recovery mode the iterator ignores the number of events in the
+// header, returns all the valid chunks until the first truncated or
+// corrupted one, and then emits closing events (value 0) for all the
+// regions that were open in that point, the innermost first. The point
+// events (see NameSet::set_point_event) are not regions and are never
+// closed.
 struct TraceIterator {
     pub(crate) header: bufferinfo::TraceHeader,
-    buf_reader: std::io::BufReader<File>,
+    path: std::path::PathBuf,
+    /// Limited to the file size, so a corrupted chunk header cannot
+    /// claim more data than the file has.
+    buf_reader: std::io::Take<std::io::BufReader<File>>,
     remaining: usize,
+    chunk: std::vec::IntoIter<event::EventEntry>,
+    recover: bool,
+    /// Stack of open regions (value != 0) and last header in recovery
+    /// mode.
+    open_regions: Vec<u16>,
+    point_events: std::sync::Arc<std::collections::BTreeSet<u16>>,
+    last_hdr: Option<event::EventHeader>,
+    counter: usize,
 }
 
 impl TraceIterator {
-    fn open(path: &std::path::Path) -> Self {
-        let file = File::open(path).expect("Error opening file");
+    fn open(
+        path: &std::path::Path,
+        recover: bool,
+        point_events: std::sync::Arc<std::collections::BTreeSet<u16>>
+    ) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
         let mut buf_reader = std::io::BufReader::new(file);
 
         const HDRSIZE: usize = std::mem::size_of::<bufferinfo::TraceHeader>();
 
         // Allocate a buffer to read the structs
         let mut tmp = vec![0u8; HDRSIZE];
-        buf_reader.read_exact(&mut tmp).expect("Error reading header from file");
+        buf_reader.read_exact(&mut tmp)?;
 
         let header: bufferinfo::TraceHeader = unsafe {
             *(tmp.as_ptr() as *const bufferinfo::TraceHeader) 
         };
 
+        codec::Codec::from_u32(header.codec)?;
+
         let remaining = header.total_flushed as usize;
 
-        Self {header, buf_reader, remaining}
+        Ok(Self {
+            header,
+            path: path.to_path_buf(),
+            buf_reader: buf_reader.take(file_len - HDRSIZE as u64),
+            remaining,
+            chunk: Vec::new().into_iter(),
+            recover,
+            open_regions: Vec::new(),
+            point_events,
+            last_hdr: None,
+            counter: 0
+        })
+    }
+
+    fn next_chunk(&mut self) -> Option<event::EventEntry>
+    {
+        if let Some(entry) = self.chunk.next() {
+            return Some(entry);
+        }
+
+        match codec::read_chunk(&mut self.buf_reader, self.header.codec()) {
+            Ok(Some(chunk)) => {
+                self.chunk = chunk.into_iter();
+                self.chunk.next()
+            },
+            Ok(None) if self.recover || self.remaining == 0 => None,
+            Ok(None) => panic!(
+                "Trace file {} is truncated ({} events missing), use the merger --recover option",
+                self.path.display(), self.remaining
+            ),
+            Err(error) if self.recover => {
+                eprintln!("Trace file {}: {} after {} events, the rest is ignored",
+                    self.path.display(), error, self.counter);
+                None
+            },
+            Err(error) => panic!(
+                "Trace file {} is corrupted ({}), use the merger --recover option",
+                self.path.display(), error
+            ),
+        }
+    }
+
+    /// Next synthesized closing event in recovery mode.
+    fn next_closing(&mut self) -> Option<event::EventEntry>
+    {
+        let id = self.open_regions.pop()?;
+        Some(event::EventEntry {
+            hdr: self.last_hdr?,
+            info: event::EventInfo { id, value: 0 }
+        })
     }
 }
 
@@ -41,23 +116,36 @@ impl Iterator for TraceIterator {
 
     fn next(&mut self) -> Option<Self::Item>
     {
-        if self.remaining == 0 {
-            return None;
+        if !self.recover {
+            if self.remaining == 0 {
+                return None;
+            }
+
+            let entry = self.next_chunk()?;
+            self.remaining -= 1;
+            self.counter += 1;
+            return Some(entry);
         }
 
-        let mut entry = event::EventEntry::default();
+        let Some(entry) = self.next_chunk() else {
+            return self.next_closing();
+        };
 
-        match self.buf_reader.read_exact(
-            unsafe {
-                std::slice::from_raw_parts_mut(
-                    &mut entry as *mut _ as *mut u8,
-                    std::mem::size_of::<event::EventEntry>()
-                )
+        // Only the regions are closed, not the counters, the callers or
+        // the point events.
+        if !crate::nameset::NameSet::is_counter_event(entry.info.id)
+            && event::CallerKind::from_event_id(entry.info.id).is_none()
+            && !self.point_events.contains(&entry.info.id) {
+            if entry.info.value != 0 {
+                self.open_regions.push(entry.info.id);
+            } else if let Some(position) = self.open_regions.iter().rposition(|&id| id == entry.info.id) {
+                self.open_regions.remove(position);
             }
-        ) {
-            Ok(_) => Some(entry),
-            Err(_) => None
         }
+
+        self.last_hdr = Some(entry.hdr);
+        self.counter += 1;
+        Some(entry)
     }
 }
 
@@ -86,46 +174,320 @@ impl std::fmt::Display for ExtendedEvent {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "2:{}:{}:{}:{}:{}", self.core, 1, 1, self.tid, self.time)?;
         for &event in self.events.iter() {
-            write!(f, ":{}:{}", event.id, event.value)?;
+            write!(f, ":{}:{}", event::paraver_type(event.id), event.value)?;
         }
         write!(f, "")
     }
 }
 
-pub(crate) struct Merger
+/// K-way merge of the trace files.
+///
+/// This iterator keeps the first pending entry of every thread in a
+/// reversed priority queue (BinaryHeap) with 1 entry/thread. Every
+/// call to next returns the entry with the lower timestamp grouped
+/// with the consecutive entries with the same header from the same
+/// thread; then the heap is restored with the next entry from the
+/// same trace file.
+///
+/// Only one entry per thread is kept in memory, so the memory use
+/// depends on the number of threads and not in the number of events.
+///
+/// The iterator also returns the index of the file every event comes
+/// from (offset + position in trace_iters). Events with the same time
+/// are sorted by that index; so merging subsets of the files
+/// produces the same order than merging all of them at once.
+struct EventMerger {
+    trace_iters: Vec<TraceIterator>,
+    offset: usize,
+    heap: std::collections::BinaryHeap<std::cmp::Reverse<(event::EventEntry, usize)>>,
+    counter: u32,
+}
+
+impl EventMerger {
+    fn new(mut trace_iters: Vec<TraceIterator>, offset: usize) -> Self
+    {
+        let mut heap = std::collections::BinaryHeap::new();
+        let mut counter = 0;
+
+        for (index, trace_iter) in trace_iters.iter_mut().enumerate() {
+            if let Some(entry) = trace_iter.next() {
+                heap.push(std::cmp::Reverse((entry, index)));
+                counter += 1;
+            }
+        }
+
+        Self { trace_iters, offset, heap, counter }
+    }
+}
+
+impl Iterator for EventMerger {
+    type Item = (ExtendedEvent, usize);
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        let std::cmp::Reverse((entry, index)) = self.heap.pop()?;
+
+        let mut ext_entry = ExtendedEvent::new(self.trace_iters[index].header.id, &entry);
+
+        for next_entry in self.trace_iters[index].by_ref() {
+            self.counter += 1;
+
+            if next_entry.hdr == entry.hdr {
+                ext_entry.events.push(next_entry.info);
+            } else {
+                assert!(next_entry.hdr.time > entry.hdr.time);
+                self.heap.push(std::cmp::Reverse((next_entry, index)));
+                break;
+            }
+        }
+
+        Some((ext_entry, self.offset + index))
+    }
+}
+
+/// Parallel version of the EventMerger.
+///
+/// The trace files are split in contiguous subsets and every subset
+/// is merged by an EventMerger in its own thread. The merged events
+/// are sent in batches through bounded channels, so the memory use is
+/// still bounded. This iterator performs the final merge of the
+/// subsets with the same (time, file index) order than the
+/// EventMerger, so the output is identical to the sequential one.
+struct ParallelMerger {
+    receivers: Vec<std::sync::mpsc::Receiver<Vec<(ExtendedEvent, usize)>>>,
+    batches: Vec<std::vec::IntoIter<(ExtendedEvent, usize)>>,
+    heap: std::collections::BinaryHeap<std::cmp::Reverse<(u64, usize, usize)>>,
+    pending: Vec<Option<ExtendedEvent>>,
+    workers: Vec<std::thread::JoinHandle<u32>>,
+    counter: u32,
+}
+
+impl ParallelMerger {
+    const BATCH_SIZE: usize = 4096;
+    const CHANNEL_BATCHES: usize = 4;
+
+    fn new(mut trace_iters: Vec<TraceIterator>, nthreads: usize) -> Self
+    {
+        let group_size = trace_iters.len().div_ceil(nthreads.max(1));
+
+        let mut receivers = Vec::new();
+        let mut workers = Vec::new();
+        let mut offset = 0;
+
+        while !trace_iters.is_empty() {
+            let rest = trace_iters.split_off(group_size.min(trace_iters.len()));
+            let group = std::mem::replace(&mut trace_iters, rest);
+            let group_len = group.len();
+
+            let (sender, receiver) = std::sync::mpsc::sync_channel(Self::CHANNEL_BATCHES);
+
+            workers.push(std::thread::spawn(move || {
+                let mut merger = EventMerger::new(group, offset);
+                loop {
+                    let batch: Vec<_> = merger.by_ref().take(Self::BATCH_SIZE).collect();
+                    if batch.is_empty() || sender.send(batch).is_err() {
+                        break;
+                    }
+                }
+                merger.counter
+            }));
+
+            receivers.push(receiver);
+            offset += group_len;
+        }
+
+        let mut merger = Self {
+            batches: (0..receivers.len()).map(|_| Vec::new().into_iter()).collect(),
+            pending: (0..receivers.len()).map(|_| None).collect(),
+            heap: std::collections::BinaryHeap::new(),
+            receivers,
+            workers,
+            counter: 0,
+        };
+
+        for group in 0..merger.receivers.len() {
+            merger.refill(group);
+        }
+
+        merger
+    }
+
+    /// Get the next event from a group and push it in the heap.
+    fn refill(&mut self, group: usize)
+    {
+        let next = self.batches[group].next().or_else(|| {
+            self.batches[group] = self.receivers[group].recv().ok()?.into_iter();
+            self.batches[group].next()
+        });
+
+        if let Some((event, index)) = next {
+            self.heap.push(std::cmp::Reverse((event.time, index, group)));
+            self.pending[group] = Some(event);
+        }
+    }
+
+    /// Wait for the workers and return the total number of entries
+    /// they read.
+    fn join(&mut self) -> u32
+    {
+        for worker in self.workers.drain(..) {
+            self.counter += worker.join().expect("Merge worker failed");
+        }
+        self.counter
+    }
+}
+
+impl Iterator for ParallelMerger {
+    type Item = (ExtendedEvent, usize);
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        let std::cmp::Reverse((_, index, group)) = self.heap.pop()?;
+        let event = self.pending[group].take().expect("Missing pending event");
+        self.refill(group);
+        Some((event, index))
+    }
+}
+
+/// Paraver trace generator.
+///
+/// Merges all the Trace_N.bin files in a trace directory into a
+/// Trace.prv file. This is done automatically at the end of the
+/// execution when automerge is enabled, or with the merger
+/// executable.
+pub struct Merger
 {
     dir_path: std::path::PathBuf,
     file_paths: Vec<std::path::PathBuf>,
-    events: Vec<ExtendedEvent>,
+    trace_iters: Vec<TraceIterator>,
     threads: std::collections::BTreeSet<u32>,
-    cores: std::collections::BTreeSet<u16>,
-    start_global_time: u64
+    total_events: u32,
+    start_global_time: u64,
+    merge_threads: usize,
+    recover: bool,
+    /// The resolved callers, when they were loaded before the merge
+    callers: Option<crate::symbols::CallerTables>
 }
 
 impl Merger {
 
-    pub(crate) fn new(dir: &std::path::Path) -> Self
+    /// Width of the fields in the prv header that are only known
+    /// after all the events are written. They are zero padded in
+    /// order to patch the header in place.
+    const DURATION_WIDTH: usize = 20;
+    const CORES_WIDTH: usize = 5;
+
+    /// Open all the trace files in the directory.
+    ///
+    /// Only the file headers are read here, the events are read in
+    /// streaming while the prv file is written.
+    ///
+    /// When merge_threads > 1 the files are merged in parallel by
+    /// subsets (see ParallelMerger). The output is the same.
+    ///
+    /// The recover mode merges traces from executions that did not
+    /// finish properly. It salvages all the complete chunks in the
+    /// files and closes the regions that were open at the truncation
+    /// point. The point events listed in the pcf are not closed; when
+    /// there is no pcf all the events are treated as regions.
+    ///
+    /// Fails when the directory cannot be read, there are no trace
+    /// files or (without recover) some file cannot be opened.
+    pub fn new(dir: &std::path::Path, merge_threads: usize, recover: bool) -> std::io::Result<Self>
     {
-        let file_paths = Merger::get_files_with_extension(dir, "bin");
-        let (events, threads, cores, start_global_time)
-            = Merger::merge_files(&file_paths);
+        let mut file_paths = Merger::get_files_with_extension(dir, "bin")?;
+
+        // read_dir order is arbitrary, sort it to make the merge order
+        // (events with the same time) reproducible.
+        file_paths.sort();
+
+        let point_events = std::sync::Arc::new(
+            if recover { Merger::read_point_events(dir) } else { Default::default() }
+        );
+
+        let mut trace_iters = Vec::new();
+
+        for path in file_paths.iter() {
+            match TraceIterator::open(path.as_path(), recover, point_events.clone()) {
+                Ok(trace_iter) => trace_iters.push(trace_iter),
+                Err(error) if recover => {
+                    eprintln!("Ignoring trace file {}: {}", path.display(), error);
+                },
+                Err(error) => return Err(std::io::Error::new(
+                    error.kind(),
+                    format!("cannot open trace file {}: {}", path.display(), error)
+                )),
+            }
+        }
 
-        Self {
+        if trace_iters.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("there are no trace files to merge in {}", dir.display())
+            ));
+        }
+
+        let all_equal = trace_iters.windows(2)
+            .all(|pair| pair[0].header.start_gtime == pair[1].header.start_gtime);
+
+        if !all_equal {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "some global time differs in trace headers"
+            ));
+        }
+
+        let start_global_time: u64 = trace_iters[0].header.start_gtime;
+        let total_events: u32 = trace_iters.iter().map(|item| item.header.total_flushed).sum();
+        let threads: std::collections::BTreeSet::<u32>
+            = trace_iters.iter().map(|item| item.header.id).collect();
+
+        Ok(Self {
             dir_path: std::path::PathBuf::from(dir),
             file_paths,
-            events, threads, cores, start_global_time
-        }
+            trace_iters,
+            threads,
+            total_events,
+            start_global_time,
+            merge_threads,
+            recover,
+            callers: None
+        })
+    }
+
+    /// Use the callers already resolved at finalize instead of
+    /// resolving them again in create_prv.
+    pub(crate) fn with_callers(mut self, callers: crate::symbols::CallerTables) -> Self
+    {
+        self.callers = Some(callers);
+        self
     }
 
 
+    /// Read the point events from the pcf metadata
+    /// (see NameSet::set_point_event).
+    fn read_point_events(dir: &std::path::Path) -> std::collections::BTreeSet<u16>
+    {
+        let Ok(pcf) = std::fs::read_to_string(dir.join("Trace.pcf")) else {
+            eprintln!("No Trace.pcf in {}, all the open events are closed", dir.display());
+            return Default::default();
+        };
+
+        pcf.lines()
+            .take_while(|line| line.starts_with("#META "))
+            .filter_map(|line| line.strip_prefix("#META point_events="))
+            .flat_map(|ids| ids.split(','))
+            .filter_map(|id| id.parse().ok())
+            .collect()
+    }
+
     /// Get a vector of paths for all the files with a given extension
     /// inside the given path.
     fn get_files_with_extension(
         dir: &std::path::Path,
         extension: &str
-    ) -> Vec<std::path::PathBuf> {
-        std::fs::read_dir(dir)
-            .expect("Failed to read directory")
+    ) -> std::io::Result<Vec<std::path::PathBuf>> {
+        Ok(std::fs::read_dir(dir)?
             .filter_map(
                 |entry| {
                     let entry = entry.ok()?;
@@ -138,122 +500,295 @@ impl Merger {
                     None
                 }
             )
-            .collect()
+            .collect())
     }
 
-    // This creates a Paraver trace from the merged information.
-    pub(crate) fn create_prv(&self, trace_dir: &std::path::Path) -> std::io::Result<()>
-    {
-        assert!(!self.events.is_empty(), "The events list is empty");
-
-        let file = std::fs::File::create(trace_dir.join("Trace.prv"))?;
-        let mut writer = std::io::BufWriter::new(file);
+    fn write_header<W: Write>(
+        &self,
+        writer: &mut W,
+        duration: u64,
+        max_core: u16
+    ) -> std::io::Result<()> {
 
         // Convert u64 timestamp to DateTime<Utc>
         let datetime: chrono::DateTime<chrono::Local>
             = chrono::Local.timestamp_opt(self.start_global_time as i64, 0).unwrap();
 
-        // Print the header
         writeln!(
             writer,
-            "#Paraver ({}):{}_ns:1({}):1:1({}:1)",
+            "#Paraver ({}):{:0dwidth$}_ns:1({:0cwidth$}):1:1({}:1)",
             datetime.format("%d/%m/%Y at %H:%M"),
-            self.events.last().unwrap().time - self.events.first().unwrap().time,
-            self.cores.iter().max().unwrap(),
-            self.threads.len()
-        )?;
+            duration,
+            max_core,
+            self.threads.len(),
+            dwidth = Self::DURATION_WIDTH,
+            cwidth = Self::CORES_WIDTH
+        )
+    }
 
-        println!("Cores: {:?}", self.cores);
-        println!("Threads: {:?}", self.threads);
-        println!("Total Events: {}", self.events.len());
+    /// This creates a Paraver trace merging all the trace files.
+    ///
+    /// The entries in the output are sorted by the entry timestamp
+    /// and consecutive events with same timestamp from the same
+    /// thread are grouped (see EventMerger).
+    ///
+    /// The events are written while they are merged, so the full
+    /// trace is never in memory. The header fields that depend on
+    /// the events (duration and cores) are written with fixed width
+    /// and patched at the end.
+    pub fn create_prv(&mut self, trace_dir: &std::path::Path) -> std::io::Result<()>
+    {
+        let file = std::fs::File::create(trace_dir.join("Trace.prv"))?;
+        let mut writer = std::io::BufWriter::new(file);
 
-        for event in self.events.iter() {
-            writeln!(writer, "{}", event)?;
+        // Placeholder header, same length than the final one.
+        self.write_header(&mut writer, 0, 0)?;
+
+        let trace_iters = std::mem::take(&mut self.trace_iters);
+
+        // The caller events contain addresses, resolved here
+        let callers = match self.callers.take() {
+            Some(callers) => Some(callers),
+            None => crate::symbols::CallerTables::load(&self.dir_path)?,
+        };
+        let mut caller_levels = std::collections::BTreeMap::<event::CallerKind, u16>::new();
+
+        let mut cores = std::collections::BTreeSet::<u16>::new();
+        let mut first_time: Option<u64> = None;
+        let mut last_time: u64 = 0;
+        let mut n_events: usize = 0;
+
+        let mut write_event = |mut event: ExtendedEvent| -> std::io::Result<()> {
+            first_time.get_or_insert(event.time);
+            last_time = event.time;
+            cores.insert(event.core);
+            n_events += event.events.len();
+
+            if let Some(callers) = &callers {
+                for info in event.events.iter_mut() {
+                    if let Some((kind, level)) = event::CallerKind::from_event_id(info.id) {
+                        info.value = callers.translate(kind, info.value);
+
+                        let max_level = caller_levels.entry(kind).or_default();
+                        *max_level = (*max_level).max(level);
+                    }
+                }
+            }
+
+            writeln!(writer, "{}", event)
+        };
+
+        let counter = if self.merge_threads > 1 && trace_iters.len() > 1 {
+            let mut merger = ParallelMerger::new(trace_iters, self.merge_threads);
+            for (event, _) in merger.by_ref() {
+                write_event(event)?;
+            }
+            merger.join()
+        } else {
+            let mut merger = EventMerger::new(trace_iters, 0);
+            for (event, _) in merger.by_ref() {
+                write_event(event)?;
+            }
+            merger.counter
+        };
+
+        assert!(first_time.is_some(), "The events list is empty");
+
+        if self.recover {
+            eprintln!("Recovered {} events, {} in trace headers", counter, self.total_events);
+        } else {
+            assert_eq!(self.total_events, counter);
+        }
+
+        if let Some(callers) = &callers {
+            callers.write_pcf(trace_dir, &caller_levels)?;
         }
 
+        let mut file = writer.into_inner()?;
+        file.seek(std::io::SeekFrom::Start(0))?;
+        self.write_header(
+            &mut file,
+            last_time - first_time.unwrap(),
+            *cores.iter().max().unwrap()
+        )?;
+
+        println!("Cores: {:?}", cores);
+        println!("Threads: {:?}", self.threads);
+        println!("Total Events: {}", n_events);
+
         Ok(())
     }
+}
 
 
-    /// This function merges multiple trace files into a single
-    /// sequential buffer.  The entries in the output are sorted by
-    /// the entry timestamp and consecutive events with same timestamp
-    /// from the same thread are grouped.
-    ///
-    /// The parsing process uses a new method totally different from
-    /// the ones in ExtraeWin (loading the files in memory and merging
-    /// by pairs)
-    ///
-    /// This function instead opens all the trace files
-    /// simultaneously.  The first entries in every thread are saved
-    /// in a reversed priority queue (BinaryHeap) with 1 entry/thread.
-    ///
-    /// The principal loop requests the next entry from the BinaryHeap
-    /// (the one with the lower timestamp) and restores it with the
-    /// next one from the same trace file.
-    ///
-    /// The TraceIterator class use std::io::BufReader to reduce
-    /// system call and improve read speed.
-    fn merge_files(
-        file_paths: &Vec<std::path::PathBuf>
-    ) -> (Vec<ExtendedEvent>,
-          std::collections::BTreeSet<u32>,
-          std::collections::BTreeSet<u16>,
-          u64
-    ) {
-        let mut trace_iters: Vec<_>
-            = file_paths
-                .iter()
-                .map(|path| TraceIterator::open(path.as_path()))
-                .collect();
+#[cfg(test)]
+mod profiler {
 
-        let total_events: u32 = trace_iters.iter().map(|item| item.header.total_flushed).sum();
+    use super::*;
+    use std::str::FromStr;
 
-        let all_equal = trace_iters.windows(2)
-            .all(|pair| pair[0].header.start_gtime == pair[1].header.start_gtime);
+    /// Create nthreads trace files with nevents each one.
+    /// The time function receives (thread, event)
+    fn create_trace_files(
+        dir: &std::path::Path,
+        nthreads: u32,
+        nevents: u64,
+        time: fn(u32, u64) -> u64,
+        core: fn(u32, u64) -> u16
+    ) -> Vec<(u32, event::EventEntry)> {
+        std::fs::create_dir_all(dir).unwrap();
+
+        let tid = std::thread::current().id();
+        let mut events: Vec<(u32, event::EventEntry)> = Vec::new();
+
+        for id in 1..=nthreads {
+            let mut info = bufferinfo::BufferInfo::new(
+                id, &tid, &std::time::Duration::default(), codec::Codec::Compact, 16
+            );
+
+            for i in 0..nevents {
+                let entry = event::EventEntry {
+                    hdr: event::EventHeader { time: time(id, i), core: core(id, i) },
+                    info: event::EventInfo { id: 1, value: i as u32 }
+                };
+                info.entries.push(entry);
+                events.push((id, entry));
+            }
 
-        assert!(all_equal, "Some global time differs in trace headers");
+            let mut file = std::fs::File::create(dir.join(format!("Trace_{}.bin", id))).unwrap();
+            info.flush_to_file(&mut file).unwrap();
+        }
 
-        let start_time: u64 = trace_iters[0].header.start_gtime;
+        events
+    }
 
-        let mut events = Vec::<ExtendedEvent>::with_capacity(total_events as usize);
-        let mut cores = std::collections::BTreeSet::<u16>::new();
-        let threads: std::collections::BTreeSet::<u32>
-            = trace_iters.iter().map(|item| item.header.id).collect();
+    #[test]
+    fn merger_streaming()
+    {
+        let dir = std::path::PathBuf::from_str("/tmp/merger_streaming").unwrap();
+        let mut events = create_trace_files(&dir, 3, 4, |id, i| i * 10 + id as u64, |id, _| id as u16);
 
-        let mut counter = 0;
+        Merger::new(&dir, 1, false).unwrap().create_prv(&dir).unwrap();
 
-        let mut heap = std::collections::BinaryHeap::new();
+        let prv = std::fs::read_to_string(dir.join("Trace.prv")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
 
-        for (index, trace_iter) in trace_iters.iter_mut().enumerate() {
-            if let Some(entry) = trace_iter.next() {
-                heap.push(std::cmp::Reverse((entry, index)));
-                counter += 1;
-            }
+        let mut lines = prv.lines();
+        let header = lines.next().unwrap();
+        assert!(header.ends_with(":00000000000000000032_ns:1(00003):1:1(3:1)"), "{}", header);
+
+        events.sort_by_key(|(_, entry)| entry.hdr.time);
+        for ((id, entry), line) in events.iter().zip(lines) {
+            assert_eq!(line, ExtendedEvent::new(*id, entry).to_string());
         }
+    }
 
-        while let Some(std::cmp::Reverse((entry, index))) = heap.pop() {
+    #[test]
+    fn merger_parallel()
+    {
+        let dir = std::path::PathBuf::from_str("/tmp/merger_parallel").unwrap();
+
+        // Many repeated times among threads to check the ties order.
+        // The cores don't follow the files and repeat among them, so
+        // the ties are not broken by the core.
+        create_trace_files(
+            &dir, 13, 10000,
+            |id, i| i * 3 + (id % 4) as u64,
+            |id, i| ((i + (id % 2) as u64) % 3) as u16
+        );
+
+        Merger::new(&dir, 1, false).unwrap().create_prv(&dir).unwrap();
+        let sequential = std::fs::read(dir.join("Trace.prv")).unwrap();
+
+        for nthreads in [2, 4, 13, 20] {
+            Merger::new(&dir, nthreads, false).unwrap().create_prv(&dir).unwrap();
+            let parallel = std::fs::read(dir.join("Trace.prv")).unwrap();
+            assert!(sequential == parallel, "Output differs with {} threads", nthreads);
+        }
 
-            let mut ext_entry = ExtendedEvent::new(trace_iters[index].header.id, &entry);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-            while let Some(next_entry) = trace_iters[index].next() {
-                counter += 1;
+    #[test]
+    fn merger_recover()
+    {
+        let dir = std::path::PathBuf::from_str("/tmp/merger_recover").unwrap();
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("Trace_1.bin");
+        // Event 4 is a point event, like a log record.
+        let counter_id = {
+            let mut name_set = crate::nameset::NameSet::new();
+            name_set.set_point_event(4);
+            name_set.create_pcf(&dir).unwrap();
+            name_set.register_counter_name("cycles")
+        };
 
-                if next_entry.hdr == entry.hdr {
-                    ext_entry.events.push(next_entry.info);
-                } else {
-                    assert!(next_entry.hdr.time > entry.hdr.time);
-                    heap.push(std::cmp::Reverse((next_entry, index)));
-                    break;
-                }
-            }
-            cores.insert(ext_entry.core);
-            events.push(ext_entry);
-        }
+        // Two complete chunks and a third one that will be truncated.
+        let chunks_size = {
+            let mut info = bufferinfo::BufferInfo::new(
+                1, &std::thread::current().id(), &std::time::Duration::default(), codec::Codec::Raw, 16
+            );
+            let mut file = std::fs::File::create(&path).unwrap();
+
+            let mut time = 0;
+            let mut push = |info: &mut bufferinfo::BufferInfo, id: u16, value: u32| {
+                time += 1;
+                info.entries.push(event::EventEntry {
+                    hdr: event::EventHeader { time, core: 0 },
+                    info: event::EventInfo { id, value }
+                });
+            };
+
+            push(&mut info, 1, 1);
+            push(&mut info, counter_id, 1);
+            push(&mut info, 2, 1);
+            push(&mut info, 4, 7);
+            info.flush_to_file(&mut file).unwrap();
+
+            push(&mut info, 2, 0);
+            push(&mut info, 3, 5);
+            info.flush_to_file(&mut file).unwrap();
+
+            let size = file.metadata().unwrap().len();
+
+            push(&mut info, 3, 0);
+            push(&mut info, 1, 0);
+            info.flush_to_file(&mut file).unwrap();
+
+            size
+        };
+
+        // Simulate a crash in the middle of the third chunk and
+        // before the header update.
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(chunks_size + 10).unwrap();
+
+        let result = std::panic::catch_unwind(|| Merger::new(&dir, 1, false).unwrap().create_prv(&dir));
+        assert!(result.is_err(), "Normal merge must fail with a truncated file");
 
-        assert_eq!(total_events, counter);
+        Merger::new(&dir, 1, true).unwrap().create_prv(&dir).unwrap();
 
-        (events, threads, cores, start_time)
+        let prv = std::fs::read_to_string(dir.join("Trace.prv")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The last line closes the open regions (3 inside 1), but not
+        // the counter or the point event.
+        assert_eq!(prv.lines().last().unwrap(), "2:0:1:1:1:6:3:5:3:0:1:0");
     }
-}
 
+    #[test]
+    fn merger_errors()
+    {
+        let dir = std::path::PathBuf::from_str("/tmp/merger_errors").unwrap();
+
+        let missing = Merger::new(&dir, 1, false);
+        assert!(missing.is_err());
+
+        std::fs::create_dir_all(&dir).unwrap();
+        let empty = Merger::new(&dir, 1, true);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(empty.err().unwrap().kind(), std::io::ErrorKind::NotFound);
+    }
+}
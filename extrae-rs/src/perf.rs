@@ -1,18 +1,25 @@
 #![allow(dead_code)]
 
-use perf_event;
-use perf_event::events::{Hardware, Software};
+use serde::Deserialize;
 
-#[derive(Debug, Clone, Copy)]
+use perf_event::events::{Cache, CacheId, CacheOp, CacheResult, Dynamic, Hardware, Raw, Software};
+
+#[derive(Debug, Clone)]
 pub(crate) enum SomeEvent {
     Hardware(perf_event::events::Hardware),
     Software(perf_event::events::Software),
+    /// Generic cache events: L1-dcache-load-misses
+    Cache(perf_event::events::Cache),
+    /// Raw cpu events: r01c2
+    Raw(perf_event::events::Raw),
+    /// Events from the PMUs in sysfs: pmu/event/
+    Dynamic(perf_event::events::Dynamic),
     None
 }
 
 impl SomeEvent {
 
-    pub(crate) const EVENTS_LIST: [(&str, SomeEvent); 15] = [
+    pub(crate) const EVENTS_LIST: [(&str, SomeEvent); 17] = [
         ("cycles", SomeEvent::Hardware(Hardware::CPU_CYCLES)),
         ("instructions", SomeEvent::Hardware(Hardware::INSTRUCTIONS)),
         ("cache-references", SomeEvent::Hardware(Hardware::CACHE_REFERENCES)),
@@ -29,94 +36,952 @@ impl SomeEvent {
         ("cpu-migrations", SomeEvent::Software(Software::CPU_MIGRATIONS)),
         ("page-faults-min", SomeEvent::Software(Software::PAGE_FAULTS_MIN)),
         ("page-faults-maj", SomeEvent::Software(Software::PAGE_FAULTS_MAJ)),
+        ("cpu-clock", SomeEvent::Software(Software::CPU_CLOCK)),
+        ("task-clock", SomeEvent::Software(Software::TASK_CLOCK)),
+    ];
+
+    /// Caches for the generic cache events, with perf's names.
+    const CACHES: [(&str, CacheId); 7] = [
+        ("L1-dcache", CacheId::L1D),
+        ("L1-icache", CacheId::L1I),
+        ("LLC", CacheId::LL),
+        ("dTLB", CacheId::DTLB),
+        ("iTLB", CacheId::ITLB),
+        ("branch", CacheId::BPU),
+        ("node", CacheId::NODE),
+    ];
+
+    const CACHE_OPS: [(&str, CacheOp); 3] = [
+        ("load", CacheOp::READ),
+        ("store", CacheOp::WRITE),
+        ("prefetch", CacheOp::PREFETCH),
     ];
 
+    /// Directory with the PMUs exposed by the kernel.
+    const PMU_DIR: &str = "/sys/bus/event_source/devices";
+
+    /// Parse a counter name. The accepted syntaxes are:
+    ///
+    /// - The generic events in EVENTS_LIST: `cycles`
+    /// - Generic cache events: `<cache>-<op>s` and `<cache>-<op>-misses`
+    ///   (`L1-dcache-loads`, `LLC-load-misses`, `dTLB-store-misses`)
+    /// - Raw events as an hexadecimal config: `r01c2`
+    /// - PMU events from sysfs: `<pmu>/<event>/` (`msr/tsc/`)
     pub(crate) fn event_from_str(event_name: &str) -> SomeEvent
     {
-        Self::EVENTS_LIST
-            .iter()
-            .find(|x| x.0 == event_name)
-            .map(|x| x.1)
+        if let Some((_, event)) = Self::EVENTS_LIST.iter().find(|x| x.0 == event_name) {
+            return event.clone();
+        }
+
+        Self::cache_from_str(event_name)
+            .or_else(|| Self::raw_from_str(event_name))
+            .or_else(|| Self::dynamic_from_str(event_name))
             .unwrap_or(SomeEvent::None)
     }
 
+    fn cache_from_str(event_name: &str) -> Option<SomeEvent>
+    {
+        let (which, rest) = Self::CACHES.iter().find_map(|(name, which)| {
+            event_name.strip_prefix(name)?.strip_prefix('-').map(|rest| (*which, rest))
+        })?;
+
+        Self::CACHE_OPS.iter().find_map(|(name, operation)| {
+            let result = match rest.strip_prefix(name)? {
+                "s" => CacheResult::ACCESS,
+                "-misses" => CacheResult::MISS,
+                _ => return None,
+            };
+            Some(SomeEvent::Cache(Cache { which, operation: *operation, result }))
+        })
+    }
+
+    fn raw_from_str(event_name: &str) -> Option<SomeEvent>
+    {
+        let config = event_name.strip_prefix('r')?;
+        if config.is_empty() || config.len() > 16 {
+            return None;
+        }
+
+        u64::from_str_radix(config, 16)
+            .ok()
+            .map(|config| SomeEvent::Raw(Raw::new(config)))
+    }
+
+    fn dynamic_from_str(event_name: &str) -> Option<SomeEvent>
+    {
+        let (pmu, event) = event_name.strip_suffix('/')?.split_once('/')?;
+        if pmu.is_empty() || event.is_empty() || event.contains('/') {
+            return None;
+        }
+
+        let event = Dynamic::builder(pmu).ok()?
+            .event(event).ok()?
+            .build().ok()?;
+
+        Some(SomeEvent::Dynamic(event))
+    }
+
+    /// Names of the generic cache events.
+    fn cache_event_names() -> Vec<String>
+    {
+        Self::CACHES.iter()
+            .flat_map(|(cache, _)| Self::CACHE_OPS.iter().flat_map(move |(op, _)| [
+                format!("{}-{}s", cache, op),
+                format!("{}-{}-misses", cache, op),
+            ]))
+            .collect()
+    }
+
+    /// Names of the events exported by the PMUs in sysfs.
+    fn dynamic_event_names() -> Vec<String>
+    {
+        let mut names = Vec::new();
+
+        let Ok(pmus) = std::fs::read_dir(Self::PMU_DIR) else {
+            return names;
+        };
+
+        for pmu in pmus.flatten() {
+            let Ok(events) = std::fs::read_dir(pmu.path().join("events")) else {
+                continue;
+            };
+
+            for event in events.flatten() {
+                let event = event.file_name().to_string_lossy().into_owned();
+                // event.scale and event.unit describe the event
+                if !event.contains('.') {
+                    names.push(format!("{}/{}/", pmu.file_name().to_string_lossy(), event));
+                }
+            }
+        }
+
+        names.sort();
+        names
+    }
+
+    /// Software events are counted by the kernel, they work without
+    /// PMU access (virtual machines, most containers).
+    fn is_software(&self) -> bool
+    {
+        matches!(self, SomeEvent::Software(_))
+    }
+
+    /// Create a perf builder for this event.
+    pub(crate) fn builder(&self) -> Option<perf_event::Builder<'static>>
+    {
+        match self {
+            SomeEvent::Hardware(hw) => Some(perf_event::Builder::new(*hw)),
+            SomeEvent::Software(sw) => Some(perf_event::Builder::new(*sw)),
+            SomeEvent::Cache(cache) => Some(perf_event::Builder::new(cache.clone())),
+            SomeEvent::Raw(raw) => Some(perf_event::Builder::new(*raw)),
+            SomeEvent::Dynamic(dynamic) => Some(perf_event::Builder::new(*dynamic)),
+            SomeEvent::None => None,
+        }
+    }
+
+}
+
+/// Problem found when checking a counter.
+#[derive(Debug)]
+pub enum CounterError {
+    /// The name is not a known counter.
+    Unknown { name: String, suggestion: Option<String> },
+    /// The kernel refused the counter because of the permissions.
+    Permission { name: String, paranoid: Option<i32>, error: std::io::Error },
+    /// The cpu (or the virtual machine) does not support the counter.
+    Unsupported { name: String, error: std::io::Error },
+    /// The modifier after the ':' is not valid.
+    InvalidModifier { name: String, modifier: char },
+    /// Any other error opening the counter.
+    Other { name: String, error: std::io::Error },
+}
+
+impl CounterError {
+    /// Classify the error returned by perf_event_open.
+    fn from_io(name: &str, error: std::io::Error) -> Self
+    {
+        let name = name.to_string();
+        match error.raw_os_error() {
+            Some(nix::libc::EACCES) | Some(nix::libc::EPERM) =>
+                CounterError::Permission { name, paranoid: perf_event_paranoid(), error },
+            Some(nix::libc::ENOENT) | Some(nix::libc::EOPNOTSUPP) | Some(nix::libc::ENODEV) =>
+                CounterError::Unsupported { name, error },
+            _ => CounterError::Other { name, error },
+        }
+    }
+}
+
+impl std::fmt::Display for CounterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CounterError::Unknown { name, suggestion: Some(suggestion) } =>
+                write!(f, "Unknown counter '{}', did you mean '{}'?", name, suggestion),
+            CounterError::Unknown { name, suggestion: None } =>
+                write!(f, "Unknown counter '{}' (run extrae-check to list the counters)", name),
+            CounterError::Permission { name, paranoid: Some(paranoid), error } if *paranoid > 2 =>
+                write!(f, "Counter '{}' not permitted ({}): perf_event_paranoid is {}, \
+                           set it to 2 or lower (sysctl kernel.perf_event_paranoid=2) \
+                           or give CAP_PERFMON to the program", name, error, paranoid),
+            CounterError::Permission { name, error, .. } =>
+                write!(f, "Counter '{}' not permitted ({}): check the perf_event_paranoid \
+                           value, the CAP_PERFMON capability or the seccomp profile \
+                           (containers usually forbid perf_event_open)", name, error),
+            CounterError::Unsupported { name, error } =>
+                write!(f, "Counter '{}' not supported by this cpu ({}): virtual machines \
+                           usually don't expose the hardware counters, but the software \
+                           ones still work", name, error),
+            CounterError::InvalidModifier { name, modifier } =>
+                write!(f, "Invalid modifier '{}' in counter '{}', the valid ones are \
+                           u (user), k (kernel), h (hypervisor) and D (pinned)", modifier, name),
+            CounterError::Other { name, error } =>
+                write!(f, "Counter '{}' failed: {}", name, error),
+        }
+    }
+}
+
+impl std::error::Error for CounterError {}
+
+/// Read /proc/sys/kernel/perf_event_paranoid
+pub fn perf_event_paranoid() -> Option<i32>
+{
+    std::fs::read_to_string("/proc/sys/kernel/perf_event_paranoid")
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Names of the generic counters supported by the profiler.
+pub fn known_counters() -> Vec<String>
+{
+    SomeEvent::EVENTS_LIST.iter().map(|(name, _)| name.to_string()).collect()
+}
+
+/// Names of all the counters that can be requested in this machine:
+/// the generic ones, the cache events and the sysfs PMU events. The raw
+/// events (rNNNN) are not listed.
+pub fn all_counters() -> Vec<String>
+{
+    let mut names = known_counters();
+    names.extend(SomeEvent::cache_event_names());
+    names.extend(SomeEvent::dynamic_event_names());
+    names
+}
+
+/// Levenshtein distance, used to suggest counter names.
+fn edit_distance(first: &str, second: &str) -> usize
+{
+    let second: Vec<char> = second.chars().collect();
+    let mut previous: Vec<usize> = (0..=second.len()).collect();
+
+    for (i, fc) in first.chars().enumerate() {
+        let mut current = vec![i + 1; second.len() + 1];
+        for (j, sc) in second.iter().enumerate() {
+            let cost = if fc == *sc { 0 } else { 1 };
+            current[j + 1] = (previous[j] + cost)
+                .min(previous[j + 1] + 1)
+                .min(current[j] + 1);
+        }
+        previous = current;
+    }
+
+    previous[second.len()]
+}
+
+/// Closest known counter name, if it is close enough to be a typo.
+fn suggest_counter(name: &str) -> Option<String>
+{
+    all_counters()
+        .into_iter()
+        .map(|known| (edit_distance(name, &known), known))
+        .filter(|(distance, _)| *distance <= 2.max(name.len() / 3))
+        .min()
+        .map(|(_, known)| known)
+}
+
+/// Check that a counter name is valid and that the kernel allows to
+/// open it in this machine.
+pub fn check_counter(name: &str) -> Result<(), CounterError>
+{
+    check_counter_with(name, PerfRefuse::None)
+}
+
+fn check_counter_with(name: &str, refuse: PerfRefuse) -> Result<(), CounterError>
+{
+    let (event, modifiers) = parse_counter(name)?;
+
+    let mut builder = event.builder().expect("Parsed counters always have a builder");
+    modifiers.apply(&mut builder);
+
+    refuse.check(&event)
+        .and_then(|_| builder.build())
+        .map(|_| ())
+        .map_err(|error| CounterError::from_io(name, error))
+}
+
+/// Event modifiers, with perf's syntax: `cycles:u`, `instructions:uk`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Modifiers {
+    user: bool,
+    kernel: bool,
+    hypervisor: bool,
+    pinned: bool,
+}
+
+impl Default for Modifiers {
+    /// Only user space, the perf_event_open default for unprivileged
+    /// users.
+    fn default() -> Self
+    {
+        Self { user: true, kernel: false, hypervisor: false, pinned: false }
+    }
+}
+
+impl Modifiers {
+    /// Parse the modifiers after the ':'. When any of u, k or h is
+    /// given, the others are excluded.
+    fn parse(name: &str, modifiers: &str) -> Result<Self, CounterError>
+    {
+        let mut output = Self { user: false, kernel: false, hypervisor: false, pinned: false };
+
+        for modifier in modifiers.chars() {
+            match modifier {
+                'u' => output.user = true,
+                'k' => output.kernel = true,
+                'h' => output.hypervisor = true,
+                'D' => output.pinned = true,
+                modifier => return Err(CounterError::InvalidModifier {
+                    name: name.to_string(),
+                    modifier
+                }),
+            }
+        }
+
+        if !(output.user || output.kernel || output.hypervisor) {
+            output.user = true;
+        }
+
+        Ok(output)
+    }
+
+    /// The counters are never inherited: every thread opens its own
+    /// counters, inheriting them would count the child threads twice.
+    pub(crate) fn apply(&self, builder: &mut perf_event::Builder)
+    {
+        builder
+            .inherit(false)
+            .exclude_user(!self.user)
+            .exclude_kernel(!self.kernel)
+            .exclude_hv(!self.hypervisor);
+    }
+}
+
+impl std::fmt::Display for Modifiers {
+    /// The canonical form of the modifiers, in perf's order.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let flags = [
+            (self.user, 'u'),
+            (self.kernel, 'k'),
+            (self.hypervisor, 'h'),
+            (self.pinned, 'D'),
+        ];
+
+        for (_, flag) in flags.iter().filter(|(set, _)| *set) {
+            write!(f, "{}", flag)?;
+        }
+        Ok(())
+    }
+}
+
+/// Split a counter specification in the event and the modifiers.
+pub(crate) fn parse_counter(name: &str) -> Result<(SomeEvent, Modifiers), CounterError>
+{
+    let (base, modifiers) = match name.rsplit_once(':') {
+        Some((base, modifiers)) => (base, Modifiers::parse(name, modifiers)?),
+        None => (name, Modifiers::default()),
+    };
+
+    match SomeEvent::event_from_str(base) {
+        SomeEvent::None => Err(CounterError::Unknown {
+            name: name.to_string(),
+            suggestion: suggest_counter(base).map(|suggestion| match name.rsplit_once(':') {
+                Some((_, modifiers)) => format!("{}:{}", suggestion, modifiers),
+                None => suggestion,
+            })
+        }),
+        event => Ok((event, modifiers)),
+    }
+}
+
+/// Name of the counter in the pcf: the event with the resolved
+/// modifiers (`instructions:ku` is `instructions:uk`, `cycles` is
+/// `cycles:u`). The equivalent counters get the same name.
+pub(crate) fn counter_pcf_name(name: &str) -> String
+{
+    let (base, modifiers) = match name.rsplit_once(':') {
+        Some((base, modifiers)) => (base, Modifiers::parse(name, modifiers).unwrap_or_default()),
+        None => (name, Modifiers::default()),
+    };
+
+    format!("{}:{}", base, modifiers)
+}
+
+/// Simulate that the kernel refuses perf_event_open, to test the
+/// fallback paths without special kernel settings. Set with the
+/// `perf_refuse` option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum PerfRefuse {
+    /// Use the real kernel answer (default).
+    #[default]
+    None,
+    /// Refuse everything but the software counters, like a virtual
+    /// machine without PMU.
+    Hardware,
+    /// Refuse all the counters, like a container with the default
+    /// seccomp profile.
+    All,
+}
+
+impl PerfRefuse {
+    pub(crate) fn check(self, event: &SomeEvent) -> std::io::Result<()>
+    {
+        let refused = match self {
+            PerfRefuse::None => false,
+            PerfRefuse::Hardware => !event.is_software(),
+            PerfRefuse::All => true,
+        };
+
+        if refused {
+            return Err(std::io::Error::from_raw_os_error(nix::libc::EACCES));
+        }
+        Ok(())
+    }
+}
+
+/// Print a profiler problem only the first time. The PerfManager and
+/// the buffers exist in every thread, and they would repeat the same
+/// messages. Every different message is printed once.
+pub(crate) fn warn_once(message: std::fmt::Arguments)
+{
+    static WARNED: std::sync::Mutex<Option<std::collections::HashSet<String>>>
+        = std::sync::Mutex::new(None);
+
+    let message = message.to_string();
+    let mut warned = WARNED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    if warned.get_or_insert_with(Default::default).insert(message.clone()) {
+        eprintln!("Profiler: {}", message);
+    }
+}
+
+/// Validation stage for the configured counters. The problems are
+/// reported and only the usable counters are returned. The counters
+/// equivalent to a previous one (`cycles` and `cycles:u`) are
+/// ignored, they would emit the same event twice.
+pub(crate) fn validate_counters(names: &[String], refuse: PerfRefuse) -> Vec<String>
+{
+    let mut usable: Vec<String> = Vec::with_capacity(names.len());
+
+    for name in names {
+        if let Err(error) = check_counter_with(name, refuse) {
+            eprintln!("Profiler: {}", error);
+            continue;
+        }
+
+        let pcf_name = counter_pcf_name(name);
+        match usable.iter().find(|other| counter_pcf_name(other) == pcf_name) {
+            Some(other) => eprintln!("Profiler: counter '{}' is the same as '{}', ignoring it", name, other),
+            None => usable.push(name.clone()),
+        }
+    }
+
+    usable
 }
 
 struct EventInfo {
     event: perf_event::Counter, // this object needs to be alive
     extrae_id: u16,
+    /// Last value read, for CounterMode::Delta
+    last: u64,
 }
 
-pub(crate) struct PerfManager {
-    pub(crate) group: perf_event::Group,
+/// How the counter values are attached to the events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CounterMode {
+    /// The value accumulated since the thread started (default).
+    #[default]
+    Cumulative,
+    /// The increment since the previous reading in the same thread,
+    /// like the classic Extrae.
+    Delta,
+}
+
+impl std::fmt::Display for CounterMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            CounterMode::Cumulative => "cumulative",
+            CounterMode::Delta => "delta",
+        };
+        f.pad(name)
+    }
+}
+
+/// Options for the PerfManager, taken from the configuration.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PerfOptions {
+    /// Maximum number of counters in a group; 0 means as many as the
+    /// kernel accepts.
+    pub(crate) counters_per_group: usize,
+    /// Scale the values of the multiplexed groups by
+    /// time_enabled/time_running.
+    pub(crate) scaling: bool,
+    pub(crate) mode: CounterMode,
+    pub(crate) refuse: PerfRefuse,
+    /// Event for the fraction (per mille) of the time that the worst
+    /// multiplexed group was counting. 0 disables it.
+    pub(crate) running_ratio_id: u16,
+}
+
+/// A group of counters scheduled together by the kernel. When the
+/// PMU has not enough registers for all the groups, the kernel
+/// multiplexes them and every group counts only part of the time.
+///
+/// Only the group leader can be pinned, so the pinned counters (`:D`)
+/// go to their own groups.
+struct CounterGroup {
+    group: perf_event::Group,
+    pinned: bool,
     events_info: Vec<EventInfo>,
 }
 
+impl CounterGroup {
+    fn new(refuse: PerfRefuse, pinned: bool) -> std::io::Result<Self>
+    {
+        refuse.check(&SomeEvent::Software(Software::DUMMY))?;
+        perf_event::Group::builder()
+            .pinned(pinned)
+            .build_group()
+            .map(|group| Self { group, pinned, events_info: Vec::new() })
+    }
+
+    fn add(
+        &mut self,
+        event: &SomeEvent,
+        modifiers: &Modifiers,
+        extrae_id: u16,
+        refuse: PerfRefuse
+    ) -> std::io::Result<()> {
+        refuse.check(event)?;
+
+        let mut builder = event.builder().ok_or_else(|| std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Invalid event name"
+        ))?;
+        modifiers.apply(&mut builder);
+
+        let event = self.group.add(&builder)?;
+        self.events_info.push(EventInfo { event, extrae_id, last: 0 });
+        Ok(())
+    }
+}
+
+/// Running ratios below this value (per mille) make the values
+/// estimations, so we warn about them.
+const MULTIPLEX_WARN_RATIO: u64 = 900;
+
+/// Ignore the ratios of the first readings, they are not
+/// representative when the group was just enabled.
+const MULTIPLEX_MIN_ENABLED: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// The multiplexing warning is printed only once per process.
+static MULTIPLEX_WARNED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Fraction (per mille) of the enabled time that a group was running.
+fn running_ratio(enabled: std::time::Duration, running: std::time::Duration) -> u64
+{
+    if enabled.is_zero() {
+        return 1000;
+    }
+    (running.as_nanos() * 1000 / enabled.as_nanos()).min(1000) as u64
+}
+
+/// Estimate the value that a multiplexed counter would have if it was
+/// running all the time. Returns None when the group never ran.
+fn scale_value(
+    value: u64,
+    enabled: std::time::Duration,
+    running: std::time::Duration
+) -> Option<u64> {
+    if running.is_zero() {
+        return enabled.is_zero().then_some(value);
+    }
+
+    if running >= enabled {
+        return Some(value);
+    }
+
+    u64::try_from(value as u128 * enabled.as_nanos() / running.as_nanos()).ok()
+}
+
+pub(crate) struct PerfManager {
+    groups: Vec<CounterGroup>,
+    options: PerfOptions,
+}
+
 impl PerfManager {
-    pub(crate) fn new(input_info: &[(String, u16)]) -> Option<Self>
+    pub(crate) fn new(input_info: &[(String, u16)], options: &PerfOptions) -> Option<Self>
     {
         if input_info.is_empty() {
             return None;
         }
 
-        let mut group = perf_event::Group::new().expect("Cannot build event group");
-
-        let events_info: Vec<EventInfo>
-            = input_info.iter().filter_map(
-                |(event_name, extrae_id)| {
-
-                    let perf_counter
-                        = match SomeEvent::event_from_str(event_name.as_str()) {
-                            SomeEvent::Hardware(hw) => group.add(&perf_event::Builder::new(hw)),
-                            SomeEvent::Software(sw) => group.add(&perf_event::Builder::new(sw)),
-                            SomeEvent::None => Err(std::io::Error::new(
-                                std::io::ErrorKind::InvalidInput,
-                                format!("Invalid event name {}", event_name),
-                            )),
-                        };
-
-                    match perf_counter {
-                        Ok(perf_counter) => {
-                            Some(EventInfo{
-                                event: perf_counter,
-                                extrae_id: *extrae_id
-                            })
-                        },
-                        Err(error) => {
-                            eprintln!("{}", error);
-                            None
-                        }
+        let mut groups: Vec<CounterGroup> = Vec::new();
+
+        for (event_name, extrae_id) in input_info {
+            let (event, modifiers) = match parse_counter(event_name) {
+                Ok(parsed) => parsed,
+                Err(error) => {
+                    warn_once(format_args!("{}, continuing without it", error));
+                    continue;
+                }
+            };
+
+            let full = groups.last().is_none_or(|last| {
+                last.pinned != modifiers.pinned
+                    || (options.counters_per_group > 0
+                        && last.events_info.len() >= options.counters_per_group)
+            });
+
+            if full {
+                match CounterGroup::new(options.refuse, modifiers.pinned) {
+                    Ok(group) => groups.push(group),
+                    Err(error) => {
+                        warn_once(format_args!("cannot create the counters group ({}), \
+                                               continuing without counters", error));
+                        break;
+                    }
+                }
+            }
+
+            let group = groups.last_mut().unwrap();
+            let Err(error) = group.add(&event, &modifiers, *extrae_id, options.refuse) else {
+                continue;
+            };
+
+            // The kernel may refuse the counter because the group does
+            // not fit in the PMU, so retry in a new group.
+            if !group.events_info.is_empty() {
+                if let Ok(mut new_group) = CounterGroup::new(options.refuse, modifiers.pinned) {
+                    if new_group.add(&event, &modifiers, *extrae_id, options.refuse).is_ok() {
+                        groups.push(new_group);
+                        continue;
                     }
                 }
-            ).collect();
+            }
+
+            warn_once(format_args!("{}, continuing without it", CounterError::from_io(event_name, error)));
+        }
+
+        groups.retain(|group| !group.events_info.is_empty());
 
-        group.reset().expect("Failed to initialize counters");
-        group.enable().expect("Error enabling counters.");
+        for group in groups.iter_mut() {
+            if let Err(error) = group.group.reset().and_then(|_| group.group.enable()) {
+                warn_once(format_args!("cannot enable the counters ({}), \
+                                       continuing without counters", error));
+                return None;
+            }
+        }
 
-        assert_eq!(input_info.len(), events_info.len());
-        assert_eq!(group.read().unwrap().len(), events_info.len());
+        if groups.is_empty() {
+            return None;
+        }
 
-        Some(Self{group, events_info})
+        Some(Self { groups, options: options.clone() })
     }
 
-    /// Add a software or hardware event.
-    pub(crate) fn get_counters(&mut self) -> Vec<(u16, u32)>
+    /// Number of counters that could be opened.
+    pub(crate) fn counters_count(&self) -> usize
     {
-        // Read the counter values
-        let entries = self.group.read().expect("Failed reading counters.");
-        assert_ne!(entries.len(), 0);
+        self.groups.iter().map(|group| group.events_info.len()).sum()
+    }
+
+    /// Number of counter groups, more than one implies multiplexing
+    /// when the PMU has not enough registers.
+    pub(crate) fn groups_count(&self) -> usize
+    {
+        self.groups.len()
+    }
 
-        entries.iter()
-            .zip(&self.events_info)
-            .filter_map(|(entry, event_info)| {
-                assert_eq!(entry.id(), event_info.event.id());
+    /// Read the counters of all the groups.
+    ///
+    /// The multiplexed values are scaled (when enabled) and the worst
+    /// running ratio is added as an extra event.
+    pub(crate) fn get_counters(&mut self) -> Vec<(u16, u32)>
+    {
+        let mut output = Vec::new();
+        let mut min_ratio = 1000;
 
-                match entry.value().try_into() {
-                    Ok(value) if value == 0 => None,
-                    Ok(value) => Some((event_info.extrae_id, value)),
-                    Err(e) => panic!("Overflow in event to value conversion: {:?}", e),
+        for group in self.groups.iter_mut() {
+            // Read the counter values. A pinned group that could not
+            // be scheduled fails here with EOF.
+            let entries = match group.group.read() {
+                Ok(entries) => entries,
+                Err(error) => {
+                    warn_once(format_args!("failed reading counters ({})", error));
+                    continue;
                 }
-            }).collect()
+            };
+            assert_ne!(entries.len(), 0);
+
+            let enabled = entries.time_enabled().unwrap_or_default();
+            let running = entries.time_running().unwrap_or_default();
+
+            if enabled >= MULTIPLEX_MIN_ENABLED {
+                min_ratio = min_ratio.min(running_ratio(enabled, running));
+            }
+
+            output.extend(entries.iter()
+                .zip(group.events_info.iter_mut())
+                .filter_map(|(entry, event_info)| {
+                    assert_eq!(entry.id(), event_info.event.id());
+
+                    let mut value = if self.options.scaling {
+                        scale_value(entry.value(), enabled, running)?
+                    } else {
+                        entry.value()
+                    };
+
+                    if self.options.mode == CounterMode::Delta {
+                        // The scaled values are estimations, they may
+                        // decrease a bit.
+                        let last = std::mem::replace(&mut event_info.last, value);
+                        value = value.saturating_sub(last);
+                    }
+
+                    match value.try_into() {
+                        Ok(0) => None,
+                        Ok(value) => Some((event_info.extrae_id, value)),
+                        Err(e) => panic!("Overflow in event to value conversion: {:?}", e),
+                    }
+                }));
+        }
+
+        if min_ratio < 1000 {
+            if self.options.running_ratio_id != 0 {
+                output.push((self.options.running_ratio_id, min_ratio as u32));
+            }
+
+            if min_ratio < MULTIPLEX_WARN_RATIO
+                && !MULTIPLEX_WARNED.swap(true, std::sync::atomic::Ordering::Relaxed) {
+                eprintln!(
+                    "Profiler: the counters are multiplexed and only count {}.{}% of the time, \
+                     the values are {}; use fewer counters for accurate values",
+                    min_ratio / 10, min_ratio % 10,
+                    if self.options.scaling { "estimations" } else { "not scaled" }
+                );
+            }
+        }
+
+        output
+    }
+
+}
+
+
+#[cfg(test)]
+mod profiler {
+
+    use super::*;
+
+    #[test]
+    fn counter_suggestions()
+    {
+        assert_eq!(edit_distance("cycles", "cycles"), 0);
+        assert_eq!(edit_distance("cyles", "cycles"), 1);
+        assert_eq!(edit_distance("", "abc"), 3);
+
+        assert_eq!(suggest_counter("cyles").as_deref(), Some("cycles"));
+        assert_eq!(suggest_counter("cache-mises").as_deref(), Some("cache-misses"));
+        assert_eq!(suggest_counter("instruction").as_deref(), Some("instructions"));
+        assert_eq!(suggest_counter("LLC-load-mises").as_deref(), Some("LLC-load-misses"));
+        assert_eq!(suggest_counter("something-else"), None);
+
+        assert!(matches!(
+            check_counter("cyles"),
+            Err(CounterError::Unknown { suggestion: Some(suggestion), .. }) if suggestion == "cycles"
+        ));
+    }
+
+    #[test]
+    fn counter_syntaxes()
+    {
+        assert!(matches!(SomeEvent::event_from_str("cycles"), SomeEvent::Hardware(_)));
+
+        match SomeEvent::event_from_str("L1-dcache-load-misses") {
+            SomeEvent::Cache(cache) => assert_eq!(cache, Cache {
+                which: CacheId::L1D, operation: CacheOp::READ, result: CacheResult::MISS
+            }),
+            event => panic!("Wrong event {:?}", event),
+        }
+
+        match SomeEvent::event_from_str("dTLB-stores") {
+            SomeEvent::Cache(cache) => assert_eq!(cache, Cache {
+                which: CacheId::DTLB, operation: CacheOp::WRITE, result: CacheResult::ACCESS
+            }),
+            event => panic!("Wrong event {:?}", event),
+        }
+
+        match SomeEvent::event_from_str("r01c2") {
+            SomeEvent::Raw(raw) => assert_eq!(raw.config, 0x01c2),
+            event => panic!("Wrong event {:?}", event),
+        }
+
+        assert!(matches!(SomeEvent::event_from_str("LLC-load"), SomeEvent::None));
+        assert!(matches!(SomeEvent::event_from_str("rxyz"), SomeEvent::None));
+        assert!(matches!(SomeEvent::event_from_str("nopmu/noevent/"), SomeEvent::None));
+
+        // Every listed name must be parseable
+        for name in all_counters() {
+            assert!(!matches!(SomeEvent::event_from_str(&name), SomeEvent::None), "{}", name);
+        }
+    }
+
+    #[test]
+    fn multiplex_scaling()
+    {
+        use std::time::Duration;
+
+        let ms = Duration::from_millis;
+
+        assert_eq!(running_ratio(ms(100), ms(100)), 1000);
+        assert_eq!(running_ratio(ms(100), ms(25)), 250);
+        assert_eq!(running_ratio(Duration::ZERO, Duration::ZERO), 1000);
+
+        assert_eq!(scale_value(10, ms(100), ms(100)), Some(10));
+        assert_eq!(scale_value(10, ms(100), ms(25)), Some(40));
+        assert_eq!(scale_value(10, Duration::ZERO, Duration::ZERO), Some(10));
+        // The group never ran, there is no estimation
+        assert_eq!(scale_value(0, ms(100), Duration::ZERO), None);
+        assert_eq!(scale_value(u64::MAX, ms(100), ms(1)), None);
+    }
+
+    #[test]
+    fn counter_groups()
+    {
+        // Software counters work without special permissions.
+        let counters = [
+            ("page-faults".to_string(), 1),
+            ("context-switches".to_string(), 2),
+            ("cpu-migrations".to_string(), 3),
+        ];
+
+        let options = PerfOptions { counters_per_group: 2, ..Default::default() };
+        let Some(mut manager) = PerfManager::new(&counters, &options) else {
+            // perf_event_paranoid does not allow any counter
+            return;
+        };
+
+        assert_eq!(manager.groups_count(), 2);
+
+        let mut page = vec![0u8; 1 << 26];
+        page.iter_mut().step_by(4096).for_each(|byte| *byte = 1);
+
+        let values = manager.get_counters();
+        assert!(values.iter().any(|(id, value)| *id == 1 && *value > 0));
+    }
+
+    #[test]
+    fn counter_delta_mode()
+    {
+        let counters = [("page-faults".to_string(), 1)];
+
+        let options = PerfOptions { mode: CounterMode::Delta, ..Default::default() };
+        let Some(mut delta) = PerfManager::new(&counters, &options) else {
+            return;
+        };
+        let mut cumulative = PerfManager::new(&counters, &PerfOptions::default()).unwrap();
+
+        // Touch new memory to generate page faults (big enough to
+        // be always mmaped by the allocator)
+        let mut read = || {
+            let mut page = vec![0u8; 1 << 26];
+            page.iter_mut().step_by(4096).for_each(|byte| *byte = 1);
+
+            [delta.get_counters(), cumulative.get_counters()].map(|values| {
+                values.iter().find(|(id, _)| *id == 1).map_or(0, |(_, value)| *value)
+            })
+        };
+
+        let [delta1, cumulative1] = read();
+        let [delta2, cumulative2] = read();
+
+        assert!(delta1 > 0 && delta2 > 0);
+        assert!(cumulative2 > cumulative1);
+        assert!(delta2 < cumulative2);
     }
 
+    #[test]
+    fn counter_refusal()
+    {
+        let counters = [
+            ("cycles".to_string(), 1),
+            ("page-faults".to_string(), 2),
+        ];
+
+        let names: Vec<String> = counters.iter().map(|(name, _)| name.clone()).collect();
+        assert!(validate_counters(&names, PerfRefuse::All).is_empty());
+        assert!(matches!(
+            check_counter_with("page-faults", PerfRefuse::All),
+            Err(CounterError::Permission { .. })
+        ));
+
+        let options = PerfOptions { refuse: PerfRefuse::All, ..Default::default() };
+        assert!(PerfManager::new(&counters, &options).is_none());
+
+        // Only the software counter survives
+        let options = PerfOptions { refuse: PerfRefuse::Hardware, ..Default::default() };
+        if let Some(manager) = PerfManager::new(&counters, &options) {
+            assert_eq!(manager.counters_count(), 1);
+        }
+    }
+
+    #[test]
+    fn counter_modifiers()
+    {
+        assert_eq!(counter_pcf_name("cycles"), "cycles:u");
+        assert_eq!(counter_pcf_name("cycles:u"), "cycles:u");
+        assert_eq!(counter_pcf_name("instructions:k"), "instructions:k");
+        assert_eq!(counter_pcf_name("instructions:ku"), "instructions:uk");
+        assert_eq!(counter_pcf_name("cycles:D"), "cycles:uD");
+        assert_eq!(counter_pcf_name("LLC-load-misses:hk"), "LLC-load-misses:kh");
+
+        // The equivalent counters are only opened once
+        let names = ["page-faults", "page-faults:u"].map(String::from);
+        if check_counter("page-faults").is_ok() {
+            assert_eq!(validate_counters(&names, PerfRefuse::None), ["page-faults"]);
+        }
+
+        let (_, modifiers) = parse_counter("page-faults:uk").unwrap();
+        assert_eq!(modifiers, Modifiers { user: true, kernel: true, hypervisor: false, pinned: false });
+
+        assert!(matches!(
+            parse_counter("cycles:x"),
+            Err(CounterError::InvalidModifier { modifier: 'x', .. })
+        ));
+        assert!(matches!(
+            parse_counter("cyles:k"),
+            Err(CounterError::Unknown { suggestion: Some(suggestion), .. }) if suggestion == "cycles:k"
+        ));
+
+        // A pinned counter goes to its own group
+        let counters = [
+            ("page-faults".to_string(), 1),
+            ("context-switches:D".to_string(), 2),
+        ];
+        if let Some(manager) = PerfManager::new(&counters, &PerfOptions::default()) {
+            assert_eq!(manager.groups_count(), 2);
+        }
+    }
 }
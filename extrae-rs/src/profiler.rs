@@ -6,9 +6,22 @@ pub struct Guard {
 }
 
 impl Guard {
+    /// Start the region. With the `caller_depth` option the call
+    /// stack is recorded too; level 1 is the function that creates
+    /// the guard.
+    #[inline(never)]
     pub fn new(id: u16, value: u32) -> Self
     {
-        crate::ThreadInfo::emplace_event_and_counters(id, value);
+        let depth = crate::GlobalInfo::as_ref().caller_depth;
+
+        if depth == 0 || id == 0 {
+            crate::ThreadInfo::emplace_event_and_counters(id, value);
+        } else {
+            // Skip this function
+            let callers = crate::symbols::callers(1, depth);
+            crate::ThreadInfo::emplace_event_counters_and_callers(id, value, &callers);
+        }
+
         Self {id}
     }
 
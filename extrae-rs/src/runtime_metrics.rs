@@ -0,0 +1,167 @@
+//! Sampler for the tokio runtime metrics.
+//!
+//! A background thread named "runtime" reads the RuntimeMetrics every
+//! `runtime_metrics_period` milliseconds and emits them as events, so
+//! they are a separate row in the trace. The queue depths are emitted
+//! as they are; the busy time (in microseconds), parks and steals are
+//! the increments since the previous sample.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::runtime::{Handle, RuntimeMetrics};
+
+/// Event ids of a worker.
+struct WorkerEvents {
+    busy: u16,
+    parks: u16,
+    #[cfg(tokio_unstable)]
+    local_queue_depth: u16,
+    #[cfg(tokio_unstable)]
+    steals: u16,
+}
+
+impl WorkerEvents {
+    fn new(worker: usize) -> Self
+    {
+        let register = |metric: &str| crate::GlobalInfo::register_point_event_name(
+            &format!("tokio_worker_{}_{}", worker, metric), None, None
+        );
+
+        Self {
+            busy: register("busy_us"),
+            parks: register("parks"),
+            #[cfg(tokio_unstable)]
+            local_queue_depth: register("local_queue_depth"),
+            #[cfg(tokio_unstable)]
+            steals: register("steals"),
+        }
+    }
+}
+
+/// Last cumulative values of a worker.
+#[derive(Default, Clone, Copy)]
+struct WorkerState {
+    busy: Duration,
+    parks: u64,
+    #[cfg(tokio_unstable)]
+    steals: u64,
+}
+
+struct MetricsReader {
+    metrics: RuntimeMetrics,
+    alive_tasks: u16,
+    global_queue_depth: u16,
+    workers: Vec<(WorkerEvents, WorkerState)>,
+}
+
+impl MetricsReader {
+    fn new(metrics: RuntimeMetrics) -> Self
+    {
+        let workers = (0..metrics.num_workers())
+            .map(|worker| (WorkerEvents::new(worker), WorkerState::default()))
+            .collect();
+
+        Self {
+            alive_tasks: crate::GlobalInfo::register_point_event_name("tokio_alive_tasks", None, None),
+            global_queue_depth: crate::GlobalInfo::register_point_event_name("tokio_global_queue_depth", None, None),
+            metrics,
+            workers,
+        }
+    }
+
+    fn saturate(value: u64) -> u32
+    {
+        value.min(u32::MAX as u64) as u32
+    }
+
+    /// Emit all the metrics with the same timestamp.
+    fn sample(&mut self)
+    {
+        let mut events = Vec::with_capacity(2 + 4 * self.workers.len());
+
+        events.push((self.alive_tasks, Self::saturate(self.metrics.num_alive_tasks() as u64)));
+        events.push((self.global_queue_depth, Self::saturate(self.metrics.global_queue_depth() as u64)));
+
+        for (worker, (ids, last)) in self.workers.iter_mut().enumerate() {
+            let busy = self.metrics.worker_total_busy_duration(worker);
+            let parks = self.metrics.worker_park_count(worker);
+
+            events.push((ids.busy, Self::saturate(busy.saturating_sub(last.busy).as_micros() as u64)));
+            events.push((ids.parks, Self::saturate(parks.saturating_sub(last.parks))));
+            last.busy = busy;
+            last.parks = parks;
+
+            #[cfg(tokio_unstable)]
+            {
+                let steals = self.metrics.worker_steal_count(worker);
+
+                events.push((ids.local_queue_depth, Self::saturate(self.metrics.worker_local_queue_depth(worker) as u64)));
+                events.push((ids.steals, Self::saturate(steals.saturating_sub(last.steals))));
+                last.steals = steals;
+            }
+        }
+
+        crate::ThreadInfo::emplace_events(&events);
+    }
+}
+
+/// Background sampler of the tokio runtime metrics. The sampling
+/// stops when it is dropped, which must happen before the main
+/// thread ends:
+///
+/// ```ignore
+/// let _sampler = RuntimeSampler::start(&tokio::runtime::Handle::current());
+/// ```
+pub struct RuntimeSampler {
+    stop: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl RuntimeSampler {
+
+    /// Start sampling the runtime every `runtime_metrics_period`
+    /// milliseconds.
+    pub fn start(handle: &Handle) -> Self
+    {
+        let period = Duration::from_millis(
+            crate::GlobalInfo::as_ref().config.runtime_metrics_period.max(1)
+        );
+
+        // The trace is finalized when the main thread ends
+        crate::ThreadInfo::with(|_| {});
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut reader = MetricsReader::new(handle.metrics());
+
+        let thread = std::thread::Builder::new()
+            .name("runtime".to_string())
+            .spawn({
+                let stop = stop.clone();
+                move || {
+                    while !stop.load(Ordering::Acquire) {
+                        reader.sample();
+                        std::thread::park_timeout(period);
+                    }
+                    // The last values before the end
+                    reader.sample();
+                }
+            })
+            .expect("Failed to start the runtime metrics thread");
+
+        Self { stop, thread: Some(thread) }
+    }
+}
+
+impl Drop for RuntimeSampler {
+    fn drop(&mut self)
+    {
+        self.stop.store(true, Ordering::Release);
+
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
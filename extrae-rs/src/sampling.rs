@@ -0,0 +1,246 @@
+//! Sampling mode: a perf sampling event per thread records the user
+//! call stack every `sampling_period` events.
+//!
+//! The samples are read from the perf ring buffer every time the
+//! thread emits an event (and when it ends), and stored in the thread
+//! buffer as sampled caller events, one per stack level. The values
+//! are indices in the address table (see symbols.rs) and the merger
+//! resolves them to functions and file:line.
+
+use perf_event::data::Record;
+use perf_event::SampleFlag;
+
+use crate::event::{CallerKind, EventHeader, MAX_CALLER_LEVELS};
+use crate::perf::{parse_counter, warn_once, PerfRefuse};
+
+/// Size of the ring buffer of every thread.
+const RING_BUFFER_BYTES: usize = 64 * 1024;
+
+/// The kernel separates the kernel and user parts of the call chain
+/// with these values (PERF_CONTEXT_*).
+const PERF_CONTEXT_MAX: u64 = -4095i64 as u64;
+
+/// Options for the sampler of every thread.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SamplingOptions {
+    /// The sampling event, empty when sampling is disabled.
+    pub(crate) event: String,
+    /// Events between two samples (nanoseconds for the clock events).
+    pub(crate) period: u64,
+    /// Stack levels recorded per sample.
+    pub(crate) depth: u16,
+    pub(crate) refuse: PerfRefuse,
+}
+
+impl SamplingOptions {
+    pub(crate) fn enabled(&self) -> bool
+    {
+        !self.event.is_empty()
+    }
+}
+
+pub(crate) struct StackSampler {
+    sampler: perf_event::Sampler,
+    depth: u16,
+}
+
+impl StackSampler {
+
+    /// Open and enable the sampling event for the current thread.
+    pub(crate) fn new(options: &SamplingOptions) -> std::io::Result<Self>
+    {
+        let (event, modifiers) = parse_counter(&options.event)
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidInput, error.to_string()))?;
+
+        options.refuse.check(&event)?;
+
+        let mut builder = event.builder()
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+        modifiers.apply(&mut builder);
+
+        let depth = options.depth.clamp(1, MAX_CALLER_LEVELS);
+
+        builder
+            .sample_period(options.period.max(1))
+            .sample(SampleFlag::TIME | SampleFlag::CALLCHAIN | SampleFlag::CPU)
+            .clockid(perf_event::Clock::new(crate::clock::global().raw_clock_id()))
+            .exclude_callchain_kernel(true)
+            .sample_max_stack(depth);
+
+        let mut sampler = builder.build()?.sampled(RING_BUFFER_BYTES)?;
+        sampler.enable()?;
+
+        Ok(Self { sampler, depth })
+    }
+
+    /// Move the pending samples to the thread buffer.
+    pub(crate) fn drain(&mut self, buffer: &mut crate::buffer::Buffer)
+    {
+        let mut entries: Vec<(u16, u32)> = Vec::with_capacity(2 * self.depth as usize);
+
+        while let Some(record) = self.sampler.next_record() {
+            let sample = match record.parse_record() {
+                Ok(Record::Sample(sample)) => sample,
+                Ok(Record::Lost(lost)) => {
+                    warn_once(format_args!(
+                        "{} samples lost, increase the sampling_period", lost.lost
+                    ));
+                    continue;
+                },
+                _ => continue,
+            };
+
+            let (Some(time), Some(callchain)) = (sample.time(), sample.callchain()) else {
+                continue;
+            };
+
+            let addresses = callchain.iter()
+                .filter(|address| (1..PERF_CONTEXT_MAX).contains(*address))
+                .take(self.depth as usize);
+
+            entries.clear();
+            for (level, address) in addresses.enumerate() {
+                // The return addresses point to the instruction after
+                // the call, which may be in the next line or function.
+                let address = if level == 0 { *address } else { address - 1 };
+                let index = crate::symbols::intern(address);
+                let level = level as u16 + 1;
+
+                entries.push((CallerKind::SampledFunction.event_id(level), index));
+                entries.push((CallerKind::SampledLine.event_id(level), index));
+            }
+
+            if entries.is_empty() {
+                continue;
+            }
+
+            let hdr = EventHeader {
+                time: crate::clock::since_start(time),
+                core: sample.cpu().map_or_else(crate::cpuid::current_core_id, |cpu| cpu as u16),
+            };
+
+            Self::emplace_sample(buffer, hdr, &entries);
+        }
+    }
+
+    /// The events of a thread must be ordered in time, and the merger
+    /// joins the consecutive events with the same time only when they
+    /// are in the same core. A sample older than the last event (or
+    /// with the same time) takes the header of the last event.
+    fn emplace_sample(buffer: &mut crate::buffer::Buffer, hdr: EventHeader, entries: &[(u16, u32)])
+    {
+        let hdr = match buffer.last_header() {
+            Some(last) if hdr.time <= last.time => last,
+            _ => hdr,
+        };
+
+        buffer.emplace_events_at(hdr, entries);
+    }
+}
+
+
+#[cfg(test)]
+mod profiler {
+
+    use super::*;
+
+    #[inline(never)]
+    fn busy_loop(iterations: u64) -> u64
+    {
+        (0..iterations).fold(0u64, |acc, i| std::hint::black_box(acc.wrapping_mul(31).wrapping_add(i)))
+    }
+
+    #[test]
+    fn sample_stacks()
+    {
+        let options = SamplingOptions {
+            event: "task-clock".to_string(),
+            period: 100_000,
+            depth: 4,
+            refuse: PerfRefuse::None,
+        };
+
+        // The sampling events may be forbidden in this machine
+        let Ok(mut sampler) = StackSampler::new(&options) else {
+            return;
+        };
+
+        let path = std::path::PathBuf::from("/tmp/sample_stacks");
+        let mut buffer = crate::buffer::Buffer::new(
+            1,
+            &std::thread::current().id(),
+            "",
+            path.clone(),
+            &std::time::Duration::default(),
+            crate::codec::Codec::Raw,
+            1 << 20
+        );
+
+        for _ in 0..16 {
+            busy_loop(1 << 22);
+            sampler.drain(&mut buffer);
+        }
+        buffer.flush().unwrap();
+        drop(buffer);
+
+        let mut file = std::fs::File::open(&path).unwrap();
+        let info = crate::BufferInfo::from_file(&mut file);
+        std::fs::remove_file(path).unwrap();
+
+        assert!(!info.entries.is_empty(), "No samples recorded");
+
+        let mut last_time = 0;
+        for entry in info.iter() {
+            let (kind, level) = CallerKind::from_event_id(entry.info.id).unwrap();
+            assert!(matches!(kind, CallerKind::SampledFunction | CallerKind::SampledLine));
+            assert!((1..=4).contains(&level));
+            assert!(entry.info.value > 0);
+            assert!(entry.hdr.time >= last_time);
+            last_time = entry.hdr.time;
+        }
+    }
+
+    #[test]
+    fn sample_older_than_last_event()
+    {
+        let dir = std::path::PathBuf::from("/tmp/sample_older_than_last_event");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut buffer = crate::buffer::Buffer::new(
+            1,
+            &std::thread::current().id(),
+            "",
+            dir.join("Trace_1.bin"),
+            &std::time::Duration::default(),
+            crate::codec::Codec::Compact,
+            1 << 20
+        );
+
+        let sample = [(CallerKind::SampledFunction.event_id(1), 1)];
+        buffer.emplace_events_at(EventHeader { time: 100, core: 0 }, &[(1, 1)]);
+
+        // Older, and with the same time, in another core
+        StackSampler::emplace_sample(&mut buffer, EventHeader { time: 50, core: 3 }, &sample);
+        StackSampler::emplace_sample(&mut buffer, EventHeader { time: 100, core: 3 }, &sample);
+        buffer.flush().unwrap();
+
+        // Also with the last event already flushed
+        StackSampler::emplace_sample(&mut buffer, EventHeader { time: 80, core: 3 }, &sample);
+        StackSampler::emplace_sample(&mut buffer, EventHeader { time: 120, core: 3 }, &sample);
+        drop(buffer);
+
+        // The merger requires strictly increasing headers for every
+        // thread.
+        crate::parser::Merger::new(&dir, 1, false).unwrap().create_prv(&dir).unwrap();
+
+        let prv = std::fs::read_to_string(dir.join("Trace.prv")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The first three samples join the event at time 100
+        let lines: Vec<&str> = prv.lines().skip(1).collect();
+        assert_eq!(lines.len(), 2, "{}", prv);
+        assert!(lines[0].starts_with("2:0:1:1:1:100:1:1:"), "{}", prv);
+        assert_eq!(lines[0].split(':').count(), 14, "{}", prv);
+        assert!(lines[1].starts_with("2:3:1:1:1:120:"), "{}", prv);
+    }
+}
@@ -2,6 +2,7 @@
 
 use tracing::{span, Event, Metadata, Subscriber};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
 
 /// This is a helper container protected with an rwlock.
@@ -14,7 +15,7 @@ use std::sync::{Arc, RwLock};
 /// But the check is only with the read lock, which significantly
 /// reduces overhead.
 #[derive(Default)]
-struct SubscriberContainer<K, V, S =  std::hash::RandomState> {
+pub(crate) struct SubscriberContainer<K, V, S =  std::hash::RandomState> {
     rwmap: Arc<RwLock<HashMap<K, V, S>>>, // Store span IDs and names
 }
 
@@ -35,7 +36,7 @@ impl<K: Eq + std::hash::Hash + Clone,
 
         {
             let read_lock = self.rwmap.read().expect("Couldn't get read subscriber");
-            if let Some(existing_value) = read_lock.get(&key) {
+            if let Some(existing_value) = read_lock.get(key) {
                 return existing_value.clone();
             }
         }
@@ -46,93 +47,269 @@ impl<K: Eq + std::hash::Hash + Clone,
     }
 }
 
-/// Implement a tracing subscriber to emit extrae events.
-/// The subscriber is useful because the tokio crate is already integrated
-/// with the tracing crate and emit events when a task starts and end,
-/// but this also works with the tracing defined macros.
-pub struct ExtraeSubscriber {
+thread_local! {
+    /// Spans entered in this thread, the last one is the current span.
+    static ENTERED_SPANS: std::cell::RefCell<Vec<span::Id>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// A span created with new_span.
+#[derive(Clone, Copy)]
+pub(crate) struct SpanInstance {
+    pub(crate) event_id: u16,
+    /// Value emitted when the span is entered. Every instance of a
+    /// span name gets its own value, so overlapping instances (like
+    /// async tasks) are separate intervals in the trace.
+    pub(crate) value: u32,
+    /// Value of the spawn location event for tokio tasks, 0 for the
+    /// other spans.
+    pub(crate) spawn: u32,
+    pub(crate) metadata: &'static Metadata<'static>,
+}
+
+struct SpanEntry {
+    instance: SpanInstance,
+    /// Handles to the span (see clone_span and try_close)
+    refs: AtomicUsize,
+}
+
+/// The mapping from tracing spans, fields and events to extrae
+/// events. Shared by the ExtraeSubscriber and the ExtraeLayer, which
+/// only differ in where they keep the live spans.
+pub(crate) struct SpanEvents {
     tokio_event_id: u16,
-    spans: SubscriberContainer<String, u16>,
+    /// Event id and instances counter of every span name.
+    spans: SubscriberContainer<String, (u16, Arc<AtomicU32>)>,
     events: SubscriberContainer<String, u32>,
+    /// Event id of every field name, 0 for the fields not recorded.
+    fields: SubscriberContainer<&'static str, u16>,
+    /// Values for the non numeric fields.
+    field_values: SubscriberContainer<(u16, String), u32>,
+    record_fields: bool,
+    /// Only with the tokio_tasks option.
+    tasks: Option<TaskEvents>,
 }
 
-impl ExtraeSubscriber {
-    pub fn new() -> Self {
-        let tokio_event_id = crate::GlobalInfo::register_event_name(
-            "tokio_event", None, None, None
+/// Events for the tokio tasks. Tokio creates a runtime.spawn span for
+/// every task (when built with `--cfg tokio_unstable`) that is
+/// entered every time the task is polled. Instead of a span event, the
+/// poll emits the task id and the spawn location, so the task can be
+/// followed across the worker threads.
+struct TaskEvents {
+    task_event_id: u16,
+    spawn_event_id: u16,
+    /// Value of every spawn location.
+    spawns: SubscriberContainer<String, u32>,
+    /// Values for the tasks without id.
+    counter: AtomicU32,
+}
+
+impl TaskEvents {
+    fn new() -> Self {
+        Self {
+            task_event_id: crate::GlobalInfo::register_event_name("tokio_task", None, None, None),
+            spawn_event_id: crate::GlobalInfo::register_event_name("tokio_task_spawn", None, None, None),
+            spawns: SubscriberContainer::default(),
+            counter: AtomicU32::new(0),
+        }
+    }
+
+    fn is_task(metadata: &Metadata<'_>) -> bool
+    {
+        metadata.name() == "runtime.spawn" && metadata.target().starts_with("tokio")
+    }
+
+    /// Value for a task id in 1..=u32::MAX.
+    fn task_value(id: u64) -> u32
+    {
+        ((id.max(1) - 1) % u32::MAX as u64 + 1) as u32
+    }
+
+    fn new_instance(&self, attrs: &span::Attributes<'_>) -> SpanInstance
+    {
+        let mut visitor = TaskVisitor::default();
+        attrs.record(&mut visitor);
+
+        let name = match visitor.name.as_deref() {
+            Some(name) if !name.is_empty() => format!("{} {} {}:{}:{}",
+                visitor.kind, name, visitor.file, visitor.line, visitor.column),
+            _ => format!("{} {}:{}:{}",
+                visitor.kind, visitor.file, visitor.line, visitor.column),
+        };
+
+        let spawn = self.spawns.get_or_insert_with(
+            &name,
+            || crate::GlobalInfo::register_event_value_name(
+                &name, None, None, self.spawn_event_id, None
+            )
+        );
+
+        // The value is the task id, the ids start at 1 and the ones
+        // above u32::MAX wrap (0 is the end of the poll). Older tokio
+        // versions have no task ids.
+        let value = match visitor.id {
+            Some(id) => Self::task_value(id),
+            None => self.counter.fetch_add(1, Ordering::Relaxed) % u32::MAX + 1,
+        };
+
+        SpanInstance { event_id: self.task_event_id, value, spawn, metadata: attrs.metadata() }
+    }
+}
+
+/// The fields of the runtime.spawn spans.
+#[derive(Default)]
+struct TaskVisitor {
+    id: Option<u64>,
+    kind: String,
+    name: Option<String>,
+    file: String,
+    line: u64,
+    column: u64,
+}
+
+impl tracing::field::Visit for TaskVisitor {
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64)
+    {
+        match field.name() {
+            "task.id" => self.id = Some(value),
+            "loc.line" => self.line = value,
+            "loc.col" => self.column = value,
+            _ => {},
+        }
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64)
+    {
+        self.record_u64(field, value.max(0) as u64);
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str)
+    {
+        match field.name() {
+            "kind" => self.kind = value.to_string(),
+            "task.name" => self.name = Some(value.to_string()),
+            "loc.file" => self.file = value.to_string(),
+            _ => {},
+        }
+    }
+
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug)
+    {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+impl SpanEvents {
+    pub(crate) fn new() -> Self {
+        let tokio_event_id = crate::GlobalInfo::register_point_event_name(
+            "tokio_event", None, None
         );
         crate::ThreadInfo::with(|_| {});
         Self {
             tokio_event_id,
             spans: SubscriberContainer::default(),
             events: SubscriberContainer::default(),
+            fields: SubscriberContainer::default(),
+            field_values: SubscriberContainer::default(),
+            record_fields: !crate::GlobalInfo::as_ref().config.span_fields.is_empty(),
+            tasks: crate::GlobalInfo::as_ref().config.tokio_tasks.then(TaskEvents::new),
         }
     }
-}
 
-impl Subscriber for ExtraeSubscriber {
-    fn enabled(&self, _: &Metadata<'_>) -> bool {
-        true
-    }
+    /// Get the event id and a new value for an instance of a span.
+    pub(crate) fn new_instance(&self, attrs: &span::Attributes<'_>) -> SpanInstance
+    {
+        let metadata = attrs.metadata();
 
-    /// Get the span id from
-    fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+        if let Some(tasks) = &self.tasks {
+            if TaskEvents::is_task(metadata) {
+                return tasks.new_instance(attrs);
+            }
+        }
 
-        let name = attrs.metadata().name().to_string();
+        let name = metadata.name().to_string();
 
-        let id: u16 = self.spans.get_or_insert_with(
+        let (event_id, counter) = self.spans.get_or_insert_with(
             &name,
             || {
-                crate::GlobalInfo::register_event_name(
+                let event_id = crate::GlobalInfo::register_event_name(
                     &name,
-                    attrs.metadata().file(),
-                    attrs.metadata().line(),
+                    metadata.file(),
+                    metadata.line(),
                     None
-                )
+                );
+                (event_id, Arc::new(AtomicU32::new(0)))
             }
         );
 
-        span::Id::from_u64(id.into())
+        // The value 0 is the end of the span
+        let value = counter.fetch_add(1, Ordering::Relaxed) % u32::MAX + 1;
+
+        SpanInstance { event_id, value, spawn: 0, metadata }
     }
 
-    fn record(&self, _id: &span::Id, _values: &span::Record)
+    /// Emit the start of a span, every time it is entered.
+    pub(crate) fn enter(&self, instance: &SpanInstance)
     {
-        // let mut visitor = EventVisitor::default();
-        // values.record(&mut visitor);
+        match &self.tasks {
+            Some(tasks) if instance.spawn != 0 => crate::ThreadInfo::emplace_events_and_counters(&[
+                (tasks.spawn_event_id, instance.spawn),
+                (instance.event_id, instance.value)
+            ]),
+            _ => crate::ThreadInfo::emplace_event_and_counters(instance.event_id, instance.value),
+        }
+    }
 
-        // let event: u16 = id.into_u64() as u16;
+    /// Emit the end of a span, every time it is exited.
+    pub(crate) fn exit(&self, instance: &SpanInstance)
+    {
+        match &self.tasks {
+            Some(tasks) if instance.spawn != 0 => crate::ThreadInfo::emplace_events_and_counters(&[
+                (tasks.spawn_event_id, 0),
+                (instance.event_id, 0)
+            ]),
+            _ => crate::ThreadInfo::emplace_event_and_counters(instance.event_id, 0),
+        }
+    }
 
-        // let value: u32 = visitor.value.expect("Record requires a value in the record! call");
+    /// Get the event id for a span field. The pcf shows the location
+    /// of the first span with the field.
+    fn field_event_id(&self, field: &tracing::field::Field, metadata: &Metadata<'_>) -> u16
+    {
+        self.fields.get_or_insert_with(
+            &field.name(),
+            || {
+                if crate::GlobalInfo::records_span_field(field.name()) {
+                    crate::GlobalInfo::register_point_event_name(
+                        field.name(),
+                        metadata.file(),
+                        metadata.line()
+                    )
+                } else {
+                    0
+                }
+            }
+        )
+    }
 
-        // match crate::GlobalInfo::get_event_value_info(event, Some(value)) {
-        //     Some(info) => {
-        //         assert_eq!(info.name, visitor.message.unwrap_or("".to_string()));
-        //     },
-        //     None => {
-        //         crate::GlobalInfo::register_event_value_name(
-        //             visitor.message.unwrap_or("".to_string()).as_str(),
-        //             None,
-        //             None,
-        //             event,
-        //             Some(value)
-        //         );
-        //     }
-        // };
+    /// Emit the selected fields of a span.
+    pub(crate) fn emit_fields(&self, metadata: &Metadata<'_>, record: &span::Record<'_>)
+    {
+        if !self.record_fields {
+            return;
+        }
 
-        // crate::ThreadInfo::emplace_event(event, value);
-    }
+        let mut visitor = FieldVisitor { mapping: self, metadata, events: Vec::new() };
+        record.record(&mut visitor);
 
-    fn record_follows_from(&self, _: &span::Id, _: &span::Id) {
-        // Handle parent/child relationships
+        crate::ThreadInfo::emplace_events(&visitor.events);
     }
 
-    /// This is emitted with the info! macro
     /// Every event receives a value id and is emitted with the
     /// tokio_event_id.
     /// The event value can be specified with the value keyword-key:
     /// info!(value = 5, "My event message")
-    fn event(&self, event: &Event<'_>) {
-
+    pub(crate) fn emit_event(&self, event: &Event<'_>)
+    {
         let mut visitor = EventVisitor::default();
         event.record(&mut visitor);
 
@@ -155,18 +332,214 @@ impl Subscriber for ExtraeSubscriber {
         );
 
         crate::ThreadInfo::emplace_event_and_counters(self.tokio_event_id, value);
+    }
+}
+
+/// Implement a tracing subscriber to emit extrae events.
+/// The subscriber is useful because the tokio crate is already integrated
+/// with the tracing crate and emit events when a task starts and end,
+/// but this also works with the tracing defined macros.
+///
+/// The span fields selected with the `span_fields` option are emitted
+/// as events when the span is created and on `Span::record`. Every
+/// field name gets its own event type; numeric fields are the event
+/// value and other fields get a value name in the pcf.
+///
+/// To combine extrae with other layers (fmt, filters) use the
+/// ExtraeLayer instead.
+pub struct ExtraeSubscriber {
+    mapping: SpanEvents,
+    /// The live spans by span::Id.
+    instances: RwLock<HashMap<u64, SpanEntry>>,
+    next_id: AtomicU64,
+}
+
+impl ExtraeSubscriber {
+    pub fn new() -> Self {
+        Self {
+            mapping: SpanEvents::new(),
+            instances: RwLock::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    fn instance(&self, id: &span::Id) -> Option<SpanInstance>
+    {
+        self.instances
+            .read()
+            .expect("Couldn't get read subscriber")
+            .get(&id.into_u64())
+            .map(|entry| entry.instance)
+    }
+}
+
+impl Default for ExtraeSubscriber {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Subscriber for ExtraeSubscriber {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        !crate::GlobalInfo::is_filtered(metadata.name())
+    }
+
+    /// Every span gets a unique id; the event id and the instance
+    /// value are stored with it until the span is closed.
+    fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+
+        let instance = self.mapping.new_instance(attrs);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        self.instances
+            .write()
+            .expect("Couldn't get write subscriber")
+            .insert(id, SpanEntry { instance, refs: AtomicUsize::new(1) });
+
+        self.mapping.emit_fields(attrs.metadata(), &span::Record::new(attrs.values()));
 
-        // This is TODO work. at the moment non-critical.
-        //println!("Event recorded: {:?}", event);
+        span::Id::from_u64(id)
+    }
+
+    /// Emit the fields recorded with Span::record
+    fn record(&self, id: &span::Id, values: &span::Record)
+    {
+        if let Some(instance) = self.instance(id) {
+            self.mapping.emit_fields(instance.metadata, values);
+        }
+    }
+
+    /// Needed by Span::current, used to record fields from
+    /// instrumented functions.
+    fn current_span(&self) -> tracing_core::span::Current {
+        ENTERED_SPANS.with(|spans| {
+            spans.borrow()
+                .last()
+                .and_then(|id| Some(tracing_core::span::Current::new(id.clone(), self.instance(id)?.metadata)))
+                .unwrap_or_else(tracing_core::span::Current::none)
+        })
+    }
 
+    fn clone_span(&self, id: &span::Id) -> span::Id {
+        if let Some(entry) = self.instances.read().expect("Couldn't get read subscriber").get(&id.into_u64()) {
+            entry.refs.fetch_add(1, Ordering::Relaxed);
+        }
+        id.clone()
+    }
+
+    /// Forget the span when the last handle is dropped.
+    fn try_close(&self, id: span::Id) -> bool {
+        {
+            let instances = self.instances.read().expect("Couldn't get read subscriber");
+            match instances.get(&id.into_u64()) {
+                Some(entry) if entry.refs.fetch_sub(1, Ordering::AcqRel) == 1 => {},
+                _ => return false,
+            }
+        }
+
+        self.instances
+            .write()
+            .expect("Couldn't get write subscriber")
+            .remove(&id.into_u64());
+        true
+    }
+
+    fn record_follows_from(&self, _: &span::Id, _: &span::Id) {
+        // Handle parent/child relationships
+    }
+
+    /// This is emitted with the info! macro, see SpanEvents::emit_event
+    fn event(&self, event: &Event<'_>) {
+        self.mapping.emit_event(event);
     }
 
     fn enter(&self, id: &span::Id) {
-        crate::ThreadInfo::emplace_event_and_counters(id.into_u64() as u16, 1);
+        let Some(instance) = self.instance(id) else {
+            return;
+        };
+
+        ENTERED_SPANS.with(|spans| spans.borrow_mut().push(id.clone()));
+        self.mapping.enter(&instance);
     }
 
     fn exit(&self, id: &span::Id) {
-        crate::ThreadInfo::emplace_event_and_counters(id.into_u64() as u16, 0);
+        let Some(instance) = self.instance(id) else {
+            return;
+        };
+
+        ENTERED_SPANS.with(|spans| {
+            let mut spans = spans.borrow_mut();
+            if let Some(position) = spans.iter().rposition(|entered| entered == id) {
+                spans.remove(position);
+            }
+        });
+        self.mapping.exit(&instance);
+    }
+}
+
+/// Collect the span fields as events. The numeric values are used as
+/// they are (saturated to u32), the others (also the booleans) are
+/// registered as value names of the field event. Paraver reads the
+/// value 0 as the end of the event, so a numeric 0 is not emitted.
+struct FieldVisitor<'a> {
+    mapping: &'a SpanEvents,
+    metadata: &'a Metadata<'a>,
+    events: Vec<(u16, u32)>,
+}
+
+impl FieldVisitor<'_> {
+    fn push_value(&mut self, field: &tracing::field::Field, value: u32)
+    {
+        let id = self.mapping.field_event_id(field, self.metadata);
+        if id != 0 && value != 0 {
+            self.events.push((id, value));
+        }
+    }
+
+    fn push_name(&mut self, field: &tracing::field::Field, name: &str)
+    {
+        let id = self.mapping.field_event_id(field, self.metadata);
+        if id == 0 {
+            return;
+        }
+
+        let value = self.mapping.field_values.get_or_insert_with(
+            &(id, name.to_string()),
+            || crate::GlobalInfo::register_event_value_name(name, None, None, id, None)
+        );
+        self.events.push((id, value));
+    }
+}
+
+impl tracing::field::Visit for FieldVisitor<'_> {
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64)
+    {
+        self.push_value(field, value.min(u32::MAX as u64) as u32);
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64)
+    {
+        self.push_value(field, value.clamp(0, u32::MAX as i64) as u32);
+    }
+
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64)
+    {
+        self.push_value(field, value as u32);
+    }
+
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool)
+    {
+        self.push_name(field, if value { "true" } else { "false" });
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str)
+    {
+        self.push_name(field, value);
+    }
+
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug)
+    {
+        self.push_name(field, &format!("{:?}", value));
     }
 }
 
@@ -186,9 +559,27 @@ impl tracing::field::Visit for EventVisitor {
 
     fn record_i64(&mut self, field: &tracing::field::Field, value: i64)
     {
-        match field.name() {
-            "value" => self.value = Some(value as u32),
-            _ => {}
+        if field.name() == "value" {
+            self.value = Some(value as u32);
         }
     }
 }
+
+
+#[cfg(test)]
+mod profiler {
+
+    use super::*;
+
+    #[test]
+    fn task_values()
+    {
+        assert_eq!(TaskEvents::task_value(1), 1);
+        assert_eq!(TaskEvents::task_value(u32::MAX as u64 - 1), u32::MAX - 1);
+        assert_eq!(TaskEvents::task_value(u32::MAX as u64), u32::MAX);
+
+        // Only the ids above u32::MAX wrap, never to 0
+        assert_eq!(TaskEvents::task_value(u32::MAX as u64 + 1), 1);
+        assert_eq!(TaskEvents::task_value(0), 1);
+    }
+}
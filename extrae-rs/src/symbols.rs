@@ -0,0 +1,445 @@
+//! Code addresses of the caller events.
+//!
+//! The caller events store the code addresses as indices in a global
+//! address table, because the event values are u32. At finalize the
+//! table is written to Trace.sym together with the loaded objects.
+//! The addresses are resolved to functions and file:line only then,
+//! loading the debug information while tracing would be too expensive.
+//! The finalize writes the names to the pcf, also without automerge,
+//! and the merger translates the event values; the merger executable
+//! resolves them again for the traces without finalize (crashes).
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Write};
+use std::sync::{LazyLock, Mutex};
+
+use crate::event::CallerKind;
+
+/// Name of the file with the address table in the trace directory.
+pub(crate) const SYMBOLS_FILE: &str = "Trace.sym";
+
+/// First line of the callers section in the pcf. The merger replaces
+/// everything after it when the trace is merged again.
+const PCF_SECTION: &str = "# Callers resolved by the merger";
+
+#[derive(Default)]
+struct AddressTable {
+    indices: HashMap<u64, u32>,
+    addresses: Vec<u64>,
+}
+
+static ADDRESSES: LazyLock<Mutex<AddressTable>> = LazyLock::new(Default::default);
+
+/// Get the index of a code address; the indices start in 1 because
+/// the value 0 means the end of an event in Paraver.
+pub(crate) fn intern(address: u64) -> u32
+{
+    let mut table = ADDRESSES.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let AddressTable { indices, addresses } = &mut *table;
+
+    *indices.entry(address).or_insert_with(|| {
+        addresses.push(address);
+        addresses.len() as u32
+    })
+}
+
+/// Return addresses in the current stack as indices in the address
+/// table, from the innermost frame. The frame of this function and
+/// the next `skip` frames are not included.
+#[inline(never)]
+pub(crate) fn callers(skip: usize, depth: u16) -> Vec<u32>
+{
+    #[cfg(target_env = "gnu")]
+    {
+        const MAX_FRAMES: usize = crate::event::MAX_CALLER_LEVELS as usize + 8;
+
+        let mut frames = [std::ptr::null_mut(); MAX_FRAMES];
+        let wanted = (1 + skip + depth as usize).min(MAX_FRAMES);
+
+        let found = unsafe {
+            nix::libc::backtrace(frames.as_mut_ptr(), wanted as nix::libc::c_int)
+        };
+
+        // The return addresses point to the instruction after the
+        // call, which may be in the next line.
+        frames[..found.max(0) as usize]
+            .iter()
+            .skip(1 + skip)
+            .map(|address| intern(*address as u64 - 1))
+            .collect()
+    }
+
+    #[cfg(not(target_env = "gnu"))]
+    {
+        let _ = (skip, depth);
+        Vec::new()
+    }
+}
+
+/// An executable or shared library loaded in the process.
+struct Module {
+    path: std::path::PathBuf,
+    /// Difference between the runtime addresses and the addresses in
+    /// the file.
+    bias: u64,
+    /// Runtime address ranges of the loaded segments.
+    ranges: Vec<(u64, u64)>,
+}
+
+/// List the loaded objects with dl_iterate_phdr.
+fn loaded_modules() -> Vec<Module>
+{
+    unsafe extern "C" fn callback(
+        info: *mut nix::libc::dl_phdr_info,
+        _size: nix::libc::size_t,
+        data: *mut nix::libc::c_void
+    ) -> nix::libc::c_int {
+        let modules = &mut *(data as *mut Vec<Module>);
+        let info = &*info;
+
+        let name = if info.dlpi_name.is_null() {
+            ""
+        } else {
+            std::ffi::CStr::from_ptr(info.dlpi_name).to_str().unwrap_or_default()
+        };
+
+        // The first object is the executable, without name.
+        let path = match name {
+            "" if modules.is_empty() => match std::env::current_exe() {
+                Ok(path) => path,
+                Err(_) => return 0,
+            },
+            "" => return 0,
+            name => std::path::PathBuf::from(name),
+        };
+
+        let bias = info.dlpi_addr;
+        let ranges = std::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize)
+            .iter()
+            .filter(|header| header.p_type == nix::libc::PT_LOAD)
+            .map(|header| (bias + header.p_vaddr, bias + header.p_vaddr + header.p_memsz))
+            .collect();
+
+        modules.push(Module { path, bias, ranges });
+        0
+    }
+
+    let mut modules: Vec<Module> = Vec::new();
+    unsafe {
+        nix::libc::dl_iterate_phdr(Some(callback), &mut modules as *mut Vec<Module> as *mut _);
+    }
+    modules
+}
+
+/// Write the address table to Trace.sym. Nothing is written when no
+/// caller events were emitted.
+///
+/// This is also called from the crash handlers, so it does not wait
+/// for the table lock; a thread that crashed holding it would block
+/// forever.
+///
+/// The format is a text file with one line per module and per
+/// address, the addresses are relative to the module file:
+///
+/// module <id> <path>
+/// address <index> <module id or -> <hex address>
+pub(crate) fn write_symbols(trace_dir: &std::path::Path) -> std::io::Result<()>
+{
+    let table = match ADDRESSES.try_lock() {
+        Ok(table) => table,
+        Err(std::sync::TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+        Err(std::sync::TryLockError::WouldBlock) => {
+            return Err(std::io::Error::from(std::io::ErrorKind::WouldBlock));
+        },
+    };
+    if table.addresses.is_empty() {
+        return Ok(());
+    }
+
+    let modules = loaded_modules();
+
+    let file = std::fs::File::create(trace_dir.join(SYMBOLS_FILE))?;
+    let mut writer = std::io::BufWriter::new(file);
+
+    writeln!(writer, "# Code addresses of the caller events, resolved by the merger")?;
+
+    for (id, module) in modules.iter().enumerate() {
+        writeln!(writer, "module {} {}", id, module.path.display())?;
+    }
+
+    for (index, address) in table.addresses.iter().enumerate() {
+        let module = modules.iter().position(|module| {
+            module.ranges.iter().any(|(start, end)| (*start..*end).contains(address))
+        });
+
+        match module {
+            Some(id) => writeln!(writer, "address {} {} {:#x}", index + 1, id, address - modules[id].bias)?,
+            None => writeln!(writer, "address {} - {:#x}", index + 1, address)?,
+        }
+    }
+
+    writer.flush()
+}
+
+/// Names for the values of one kind of caller event (functions or
+/// file:line). Equal names share the same value.
+#[derive(Default)]
+struct ValueTable {
+    /// Address index to value
+    values: Vec<u32>,
+    names: Vec<String>,
+    ids: HashMap<String, u32>,
+}
+
+impl ValueTable {
+    fn push(&mut self, name: String)
+    {
+        let next = self.names.len() as u32 + 1;
+        let value = *self.ids.entry(name.clone()).or_insert(next);
+        if value == next {
+            self.names.push(name);
+        }
+        self.values.push(value);
+    }
+
+    fn translate(&self, index: u32) -> u32
+    {
+        index.checked_sub(1)
+            .and_then(|index| self.values.get(index as usize))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+/// The resolved address table of a trace, used by the merger to
+/// translate the caller event values and to write their names in the
+/// pcf.
+pub(crate) struct CallerTables {
+    functions: ValueTable,
+    lines: ValueTable,
+}
+
+impl CallerTables {
+
+    /// Read and resolve the Trace.sym file, returns None when the
+    /// trace has no caller events.
+    pub(crate) fn load(trace_dir: &std::path::Path) -> std::io::Result<Option<Self>>
+    {
+        let file = match std::fs::File::open(trace_dir.join(SYMBOLS_FILE)) {
+            Ok(file) => file,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+
+        let invalid = |line: &str| std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid line in {}: '{}'", SYMBOLS_FILE, line)
+        );
+
+        let mut modules: Vec<(String, Option<addr2line::Loader>)> = Vec::new();
+        let mut tables = Self { functions: ValueTable::default(), lines: ValueTable::default() };
+
+        for line in std::io::BufReader::new(file).lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.splitn(4, ' ').collect();
+
+            match fields.as_slice() {
+                ["module", _, path] => {
+                    // The loader fails for the vdso and deleted files,
+                    // their addresses stay unresolved.
+                    let loader = addr2line::Loader::new(path).ok();
+                    modules.push((path.to_string(), loader));
+                },
+                ["address", _, module, address] => {
+                    let address = u64::from_str_radix(address.trim_start_matches("0x"), 16)
+                        .map_err(|_| invalid(&line))?;
+                    let module = match *module {
+                        "-" => None,
+                        id => Some(id.parse::<usize>()
+                            .ok()
+                            .and_then(|id| modules.get(id))
+                            .ok_or_else(|| invalid(&line))?),
+                    };
+
+                    let (function, location) = resolve(module, address);
+                    tables.functions.push(function);
+                    tables.lines.push(location);
+                },
+                [comment, ..] if comment.starts_with('#') => {},
+                _ => return Err(invalid(&line)),
+            }
+        }
+
+        Ok(Some(tables))
+    }
+
+    /// Value written in the prv for a caller event.
+    pub(crate) fn translate(&self, kind: CallerKind, index: u32) -> u32
+    {
+        if kind.is_line() {
+            self.lines.translate(index)
+        } else {
+            self.functions.translate(index)
+        }
+    }
+
+    /// Add the caller event types and their values to the pcf. The
+    /// levels map contains the deepest level found for every kind.
+    pub(crate) fn write_pcf(
+        &self,
+        trace_dir: &std::path::Path,
+        levels: &BTreeMap<CallerKind, u16>
+    ) -> std::io::Result<()> {
+
+        let path = trace_dir.join("Trace.pcf");
+
+        // Remove the section written by a previous merge
+        let mut pcf = std::fs::read_to_string(&path).unwrap_or_default();
+        if let Some(position) = pcf.find(PCF_SECTION) {
+            pcf.truncate(position);
+        }
+
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        writer.write_all(pcf.as_bytes())?;
+        writeln!(writer, "{}", PCF_SECTION)?;
+
+        for (kind, max_level) in levels {
+            writeln!(writer, "EVENT_TYPE")?;
+            for level in 1..=*max_level {
+                writeln!(writer, "0 {} {} at level {}", kind.paraver_type(level), kind.label(), level)?;
+            }
+
+            let table = if kind.is_line() { &self.lines } else { &self.functions };
+
+            writeln!(writer, "VALUES")?;
+            writeln!(writer, "0 End")?;
+            for (value, name) in table.names.iter().enumerate() {
+                writeln!(writer, "{} {}", value + 1, name)?;
+            }
+            writeln!(writer)?;
+        }
+
+        writer.flush()
+    }
+}
+
+/// Function and file:line of an address, or the address itself when
+/// it can not be resolved.
+fn resolve(
+    module: Option<&(String, Option<addr2line::Loader>)>,
+    address: u64
+) -> (String, String) {
+
+    let unresolved = || {
+        let name = match module {
+            Some((path, _)) => format!(
+                "{}+{:#x}",
+                std::path::Path::new(path).file_name().unwrap_or_default().to_string_lossy(),
+                address
+            ),
+            None => format!("{:#x}", address),
+        };
+        (name.clone(), name)
+    };
+
+    let Some((_, Some(loader))) = module else {
+        return unresolved();
+    };
+
+    let (unresolved_function, unresolved_line) = unresolved();
+
+    // The innermost frame (the inlined function, if any)
+    let function = loader.find_frames(address)
+        .ok()
+        .and_then(|mut frames| frames.next().ok().flatten())
+        .and_then(|frame| frame.function)
+        .and_then(|function| function.demangle().ok().map(|name| name.into_owned()))
+        .or_else(|| loader.find_symbol(address)
+            .map(|symbol| addr2line::demangle_auto(symbol.into(), None).into_owned()))
+        .unwrap_or(unresolved_function);
+
+    let line = loader.find_location(address)
+        .ok()
+        .flatten()
+        .and_then(|location| Some(format!("{}:{}", location.file?, location.line?)))
+        .unwrap_or(unresolved_line);
+
+    (function, line)
+}
+
+
+#[cfg(test)]
+mod profiler {
+
+    use super::*;
+
+    #[inline(never)]
+    fn marker_function() -> u64
+    {
+        marker_function as *const () as u64
+    }
+
+    #[test]
+    fn resolve_addresses()
+    {
+        let dir = std::path::Path::new("/tmp/resolve_addresses");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+
+        let address = marker_function();
+        let index = intern(address);
+        assert_eq!(intern(address), index);
+        assert!(index > 0);
+
+        write_symbols(dir).unwrap();
+
+        let tables = CallerTables::load(dir).unwrap().unwrap();
+        let function = tables.translate(CallerKind::Function, index);
+        assert!(tables.functions.names[function as usize - 1].contains("marker_function"));
+
+        let line = tables.translate(CallerKind::Line, index);
+        assert!(tables.lines.names[line as usize - 1].contains("symbols.rs"));
+
+        let mut levels = BTreeMap::new();
+        levels.insert(CallerKind::Function, 2);
+        tables.write_pcf(dir, &levels).unwrap();
+        tables.write_pcf(dir, &levels).unwrap();
+
+        let pcf = std::fs::read_to_string(dir.join("Trace.pcf")).unwrap();
+        assert_eq!(pcf.matches(PCF_SECTION).count(), 1);
+        assert!(pcf.contains("0 70000002 Caller at level 2"));
+        assert!(pcf.contains("marker_function"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[inline(never)]
+    fn inner_frame() -> Vec<u32>
+    {
+        let callers = callers(0, 2);
+        std::hint::black_box(callers)
+    }
+
+    #[test]
+    fn capture_callers_stack()
+    {
+        let dir = std::path::Path::new("/tmp/capture_callers_stack");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+
+        let callers = inner_frame();
+        assert_eq!(callers.len(), 2);
+
+        write_symbols(dir).unwrap();
+        let tables = CallerTables::load(dir).unwrap().unwrap();
+
+        // Level 1 is the function that called callers
+        let function = tables.translate(CallerKind::Function, callers[0]);
+        assert!(tables.functions.names[function as usize - 1].contains("inner_frame"));
+
+        let function = tables.translate(CallerKind::Function, callers[1]);
+        assert!(tables.functions.names[function as usize - 1].contains("capture_callers_stack"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
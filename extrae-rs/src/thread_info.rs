@@ -7,6 +7,9 @@ pub struct ThreadInfo {
     id: u32,
     buffer_events: crate::buffer::Buffer,
     events_manager: Option<crate::perf::PerfManager>,
+    sampler: Option<crate::sampling::StackSampler>,
+    /// The last crash flush request seen (see crash.rs).
+    flush_request: u32,
 }
 
 impl ThreadInfo {
@@ -23,10 +26,67 @@ impl ThreadInfo {
 
         buffer_events.emplace_event(GlobalInfo::as_ref().thread_event_id, 1);
 
-        let events_manager
-            = crate::perf::PerfManager::new(&GlobalInfo::as_ref().events_info);
+        let events_manager = Self::create_perf_manager();
+        let sampler = Self::create_sampler();
 
-        Self { tid, id, buffer_events, events_manager }
+        Self::TRACE_ID.with(|trace_id| trace_id.set(id));
+
+        let flush_request = crate::crash::current_flush();
+
+        Self { tid, id, buffer_events, events_manager, sampler, flush_request }
+    }
+
+    /// Flush the buffer when a crash asked for it (see crash.rs).
+    #[inline]
+    fn check_flush_request(&mut self)
+    {
+        if let Some(request) = crate::crash::pending_flush(self.flush_request) {
+            self.flush_request = request;
+            self.buffer_events.flush_or_drop();
+            crate::crash::ack_flush();
+        }
+    }
+
+    /// Open the stack sampler for this thread. The sampling was
+    /// validated at initialization, so errors here are ignored.
+    fn create_sampler() -> Option<crate::sampling::StackSampler>
+    {
+        let options = &GlobalInfo::as_ref().sampling_options;
+
+        if !options.enabled() {
+            return None;
+        }
+
+        crate::sampling::StackSampler::new(options).ok()
+    }
+
+    /// Move the pending samples to the buffer before a new event, so
+    /// the buffer stays ordered in time.
+    #[inline]
+    fn drain_samples(&mut self)
+    {
+        if let Some(sampler) = &mut self.sampler {
+            sampler.drain(&mut self.buffer_events);
+        }
+    }
+
+    /// Open the counters for this thread. When some of them fail we
+    /// continue with the others.
+    fn create_perf_manager() -> Option<crate::perf::PerfManager>
+    {
+        let global_info = GlobalInfo::as_ref();
+
+        let manager = crate::perf::PerfManager::new(
+            &global_info.events_info,
+            &global_info.perf_options
+        );
+
+        let opened = manager.as_ref().map_or(0, |manager| manager.counters_count());
+        if opened < global_info.events_info.len() {
+            GlobalInfo::notify_counters_degraded();
+        }
+
+        manager
     }
 }
 
@@ -34,6 +94,7 @@ impl Drop for ThreadInfo {
 
     fn drop(&mut self)
     {
+        self.drain_samples();
         self.buffer_events.emplace_event(GlobalInfo::as_ref().thread_event_id, 0);
         self.buffer_events.flush().expect("Failed to flush buffer data");
         GlobalInfo::notify_thread_finalized(&self.buffer_events);
@@ -45,6 +106,24 @@ impl ThreadInfo {
     // Use thread_local to define a thread-local storage
     thread_local! {
         static THREAD_INFO: ThreadInfo = ThreadInfo::new();
+
+        /// The trace id of the thread, set when THREAD_INFO is
+        /// created (0 before). The crash and fork handlers check it
+        /// because try_with would create the info (and register the
+        /// thread) if it does not exist yet.
+        static TRACE_ID: std::cell::Cell<u32> = const { std::cell::Cell::new(0) };
+    }
+
+    fn is_initialized() -> bool
+    {
+        Self::current_trace_id() != 0
+    }
+
+    /// The trace id of the current thread, 0 when it emitted nothing.
+    /// This is async-signal-safe.
+    pub(crate) fn current_trace_id() -> u32
+    {
+        Self::TRACE_ID.try_with(|trace_id| trace_id.get()).unwrap_or(0)
     }
 
     pub fn with<F, R>(f: F) -> R
@@ -55,33 +134,163 @@ impl ThreadInfo {
         ThreadInfo::THREAD_INFO.with(f)
     }
 
+    /// Events with id 0 (filtered) are ignored.
     pub fn emplace_event(id: u16, value: u32)
     {
+        if id == 0 {
+            return;
+        }
+
         ThreadInfo::THREAD_INFO.with(|info| {
             let mut_info = info as *const ThreadInfo as *mut ThreadInfo;
             unsafe {
+                (*mut_info).drain_samples();
                 (*mut_info).buffer_events.emplace_event(id, value);
+                (*mut_info).check_flush_request();
             }
         })
     }
 
+    /// Emit several events with the same timestamp. Events with id 0
+    /// (filtered) are ignored.
+    pub(crate) fn emplace_events(entries: &[(u16, u32)])
+    {
+        if entries.iter().any(|(id, _)| *id == 0) {
+            let entries: Vec<(u16, u32)> = entries.iter()
+                .copied()
+                .filter(|(id, _)| *id != 0)
+                .collect();
+            return Self::emplace_events(&entries);
+        }
+
+        if entries.is_empty() {
+            return;
+        }
+
+        ThreadInfo::THREAD_INFO.with(|info| {
+            let mut_info = info as *const ThreadInfo as *mut ThreadInfo;
+            unsafe {
+                (*mut_info).drain_samples();
+                (*mut_info).buffer_events.emplace_events(entries);
+                (*mut_info).check_flush_request();
+            }
+        })
+    }
+
+    /// Like emplace_event, and flush the buffer of this thread. It
+    /// does nothing when the thread local info does not exist or was
+    /// already destroyed. Used from the panic hook.
+    pub(crate) fn try_emplace_event_and_flush(id: u16, value: u32) -> bool
+    {
+        if !Self::is_initialized() {
+            return false;
+        }
+
+        ThreadInfo::THREAD_INFO.try_with(|info| {
+            let mut_info = info as *const ThreadInfo as *mut ThreadInfo;
+            unsafe {
+                (*mut_info).drain_samples();
+                (*mut_info).buffer_events.emplace_event(id, value);
+                (*mut_info).buffer_events.flush().is_ok()
+            }
+        }).unwrap_or(false)
+    }
+
+    /// Replace the buffer and counters of the forking thread in the
+    /// child process (see fork.rs). The events inherited from the
+    /// parent are discarded; the parent is responsible for them.
+    /// A thread that never emitted has nothing to reset, it gets its
+    /// buffer in the child on the first event.
+    pub(crate) fn reset_after_fork()
+    {
+        if !Self::is_initialized() {
+            return;
+        }
+
+        let _ = ThreadInfo::THREAD_INFO.try_with(|info| {
+            let mut_info = info as *const ThreadInfo as *mut ThreadInfo;
+            unsafe {
+                (*mut_info).buffer_events.discard();
+
+                let thread: std::thread::Thread = std::thread::current();
+                let mut buffer_events
+                    = GlobalInfo::get_thread_buffer(thread.id(), thread.name().unwrap_or_default());
+                buffer_events.emplace_event(GlobalInfo::as_ref().thread_event_id, 1);
+
+                (*mut_info).id = buffer_events.id();
+                Self::TRACE_ID.with(|trace_id| trace_id.set(buffer_events.id()));
+                (*mut_info).buffer_events = buffer_events;
+                (*mut_info).events_manager = Self::create_perf_manager();
+                (*mut_info).sampler = Self::create_sampler();
+            }
+        });
+    }
+
     pub fn emplace_event_and_counters(id: u16, value: u32)
     {
+        ThreadInfo::emplace_event_counters_and_callers(id, value, &[]);
+    }
+
+    /// Like emplace_event_and_counters, plus the caller events for
+    /// the given addresses (see symbols::callers), from level 1.
+    pub(crate) fn emplace_event_counters_and_callers(id: u16, value: u32, callers: &[u32])
+    {
+        if id == 0 {
+            return;
+        }
+
         ThreadInfo::THREAD_INFO.with(|info| {
             let mut_info = info as *const ThreadInfo as *mut ThreadInfo;
             unsafe {
+                (*mut_info).drain_samples();
 
                 match &mut (*mut_info).events_manager {
-                    Some(manager) => {
-                        let mut events = manager.get_counters();
+                    None if callers.is_empty() => {
+                        (*mut_info).buffer_events.emplace_event(id, value);
+                    },
+                    manager => {
+                        let mut events = match manager {
+                            Some(manager) => manager.get_counters(),
+                            None => Vec::with_capacity(2 * callers.len() + 1),
+                        };
+
+                        for (level, &address) in callers.iter().enumerate() {
+                            let level = level as u16 + 1;
+                            events.push((crate::event::CallerKind::Function.event_id(level), address));
+                            events.push((crate::event::CallerKind::Line.event_id(level), address));
+                        }
+
                         events.push((id, value));
                         (*mut_info).buffer_events.emplace_events(&events);
-                    },
-                    None => (*mut_info).buffer_events.emplace_event(id, value),
+                    }
                 }
+
+                (*mut_info).check_flush_request();
             }
         })
     }
 
+    /// Emit several events and the counters with the same timestamp.
+    /// Events with id 0 (filtered) are ignored.
+    pub(crate) fn emplace_events_and_counters(entries: &[(u16, u32)])
+    {
+        if entries.iter().all(|(id, _)| *id == 0) {
+            return;
+        }
+
+        ThreadInfo::THREAD_INFO.with(|info| {
+            let mut_info = info as *const ThreadInfo as *mut ThreadInfo;
+            unsafe {
+                (*mut_info).drain_samples();
 
+                let mut events = match &mut (*mut_info).events_manager {
+                    Some(manager) => manager.get_counters(),
+                    None => Vec::with_capacity(entries.len()),
+                };
+                events.extend(entries.iter().filter(|(id, _)| *id != 0));
+                (*mut_info).buffer_events.emplace_events(&events);
+                (*mut_info).check_flush_request();
+            }
+        })
+    }
 }
@@ -0,0 +1,298 @@
+use serde::Deserialize;
+
+/// What to do when the trace directory already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExistingDir {
+    /// Fail with an error (default).
+    #[default]
+    Fail,
+    /// Remove the trace files in the directory and reuse it.
+    Overwrite,
+    /// Append a counter to the name: `<name>_1`, `<name>_2`...
+    Counter,
+}
+
+impl std::fmt::Display for ExistingDir {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            ExistingDir::Fail => "fail",
+            ExistingDir::Overwrite => "overwrite",
+            ExistingDir::Counter => "counter",
+        };
+        f.pad(name)
+    }
+}
+
+/// Name of the symlink to the newest trace in the output directory.
+pub(crate) const LATEST_LINK: &str = "latest";
+
+/// Values for the trace directory template placeholders.
+pub(crate) struct TemplateValues {
+    pub(crate) program: String,
+    pub(crate) pid: u32,
+    pub(crate) hostname: String,
+    pub(crate) timestamp: u128,
+    pub(crate) tag: String,
+}
+
+impl TemplateValues {
+
+    /// Values for the current process. The tag defaults to the
+    /// timestamp when it is empty.
+    pub(crate) fn current(start_system_time: &std::time::Duration, tag: &str) -> Self
+    {
+        let program = std::env::current_exe()
+            .ok()
+            .and_then(|path| path.file_name().map(|name| name.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| "unknown".to_string());
+
+        let hostname = nix::unistd::gethostname()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| "unknown".to_string());
+
+        let timestamp = start_system_time.as_millis();
+
+        let tag = if tag.is_empty() {
+            timestamp.to_string()
+        } else {
+            tag.to_string()
+        };
+
+        Self { program, pid: std::process::id(), hostname, timestamp, tag }
+    }
+
+    /// Check that a template only uses valid placeholders.
+    pub(crate) fn check(template: &str) -> std::io::Result<()>
+    {
+        let values = Self {
+            program: String::new(),
+            pid: 0,
+            hostname: String::new(),
+            timestamp: 0,
+            tag: String::new(),
+        };
+        values.expand(template).map(|_| ())
+    }
+
+    /// Substitute the placeholders {program}, {pid}, {hostname},
+    /// {timestamp} and {tag} in the template.
+    pub(crate) fn expand(&self, template: &str) -> std::io::Result<String>
+    {
+        let mut output = String::with_capacity(template.len());
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            output.push_str(&rest[..start]);
+
+            let end = rest[start..].find('}').ok_or_else(|| std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unclosed placeholder in trace directory template: '{}'", template)
+            ))?;
+
+            match &rest[start + 1..start + end] {
+                "program" => output.push_str(&self.program),
+                "pid" => output.push_str(&self.pid.to_string()),
+                "hostname" => output.push_str(&self.hostname),
+                "timestamp" => output.push_str(&self.timestamp.to_string()),
+                "tag" => output.push_str(&self.tag),
+                other => return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Unknown placeholder {{{}}} in trace directory template: '{}'", other, template)
+                )),
+            }
+
+            rest = &rest[start + end + 1..];
+        }
+
+        output.push_str(rest);
+        Ok(output)
+    }
+}
+
+/// Check if a directory entry was created by the profiler.
+fn is_trace_entry(entry: &std::fs::DirEntry) -> bool
+{
+    let name = entry.file_name();
+    let name = name.to_string_lossy();
+    name.starts_with("Trace") || name.starts_with("fork_")
+}
+
+/// Remove the profiler files from an existing trace directory.
+///
+/// We don't remove the directory itself, so a wrong template (like
+/// ".") never deletes user files.
+fn clean_trace_dir(path: &std::path::Path) -> std::io::Result<()>
+{
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        if !is_trace_entry(&entry) {
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            std::fs::remove_dir_all(entry.path())?;
+        } else {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Create the trace directory `output_dir/name` following the policy
+/// for existing directories. The name may contain subdirectories
+/// (like `{program}/{pid}`). Returns the path to the created
+/// directory.
+pub(crate) fn create_trace_dir(
+    output_dir: &std::path::Path,
+    name: &str,
+    policy: ExistingDir
+) -> std::io::Result<std::path::PathBuf> {
+
+    let path = output_dir.join(name);
+
+    std::fs::create_dir_all(path.parent().unwrap_or(output_dir))?;
+
+    match std::fs::create_dir(&path) {
+        Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => match policy {
+            ExistingDir::Fail => Err(error),
+            ExistingDir::Overwrite => {
+                clean_trace_dir(&path)?;
+                Ok(path)
+            },
+            ExistingDir::Counter => {
+                // create_dir fails when the directory exists, so this
+                // is safe even with concurrent processes.
+                for counter in 1.. {
+                    let path = output_dir.join(format!("{}_{}", name, counter));
+                    match std::fs::create_dir(&path) {
+                        Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                        Err(error) => return Err(error),
+                        Ok(()) => return Ok(path),
+                    }
+                }
+                unreachable!()
+            }
+        },
+        Err(error) => Err(error),
+        Ok(()) => Ok(path),
+    }
+}
+
+/// Point the `latest` symlink in the output directory to the trace
+/// directory.
+///
+/// The link is created with a temporary name and renamed, so readers
+/// always see a valid link. If `latest` exists and is not a symlink we
+/// leave it untouched.
+pub(crate) fn update_latest_link(
+    output_dir: &std::path::Path,
+    trace_dir: &std::path::Path
+) -> std::io::Result<()> {
+
+    let link = output_dir.join(LATEST_LINK);
+
+    if let Ok(metadata) = std::fs::symlink_metadata(&link) {
+        if !metadata.file_type().is_symlink() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a symlink", link.display())
+            ));
+        }
+    }
+
+    // Relative target, so the output directory can be moved. The
+    // trace directory may be nested (see create_trace_dir).
+    let target = trace_dir.strip_prefix(output_dir).map_err(|_| std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("Trace directory {} is not in {}", trace_dir.display(), output_dir.display())
+    ))?;
+
+    let tmp_link = output_dir.join(format!(".{}.{}", LATEST_LINK, std::process::id()));
+    let _ = std::fs::remove_file(&tmp_link);
+
+    std::os::unix::fs::symlink(target, &tmp_link)?;
+    std::fs::rename(&tmp_link, &link)
+}
+
+
+#[cfg(test)]
+mod profiler {
+
+    use super::*;
+
+    fn values() -> TemplateValues
+    {
+        TemplateValues {
+            program: "prog".to_string(),
+            pid: 42,
+            hostname: "host".to_string(),
+            timestamp: 1000,
+            tag: "mytag".to_string(),
+        }
+    }
+
+    #[test]
+    fn template_expand()
+    {
+        let values = values();
+
+        assert_eq!(values.expand("TRACEDIR_{tag}").unwrap(), "TRACEDIR_mytag");
+        assert_eq!(
+            values.expand("{program}-{hostname}-{pid}-{timestamp}").unwrap(),
+            "prog-host-42-1000"
+        );
+        assert_eq!(values.expand("plain").unwrap(), "plain");
+
+        assert!(values.expand("TRACEDIR_{unknown}").is_err());
+        assert!(values.expand("TRACEDIR_{tag").is_err());
+    }
+
+    #[test]
+    fn existing_dir_policies()
+    {
+        let output_dir = std::path::Path::new("/tmp/existing_dir_policies");
+        let _ = std::fs::remove_dir_all(output_dir);
+
+        let first = create_trace_dir(output_dir, "TRACEDIR", ExistingDir::Fail).unwrap();
+        assert!(create_trace_dir(output_dir, "TRACEDIR", ExistingDir::Fail).is_err());
+
+        let second = create_trace_dir(output_dir, "TRACEDIR", ExistingDir::Counter).unwrap();
+        assert_eq!(second, output_dir.join("TRACEDIR_1"));
+
+        // Overwrite only removes the trace files
+        std::fs::write(first.join("Trace_1.bin"), "data").unwrap();
+        std::fs::write(first.join("notes.txt"), "data").unwrap();
+        let third = create_trace_dir(output_dir, "TRACEDIR", ExistingDir::Overwrite).unwrap();
+        assert_eq!(third, first);
+        assert!(!first.join("Trace_1.bin").exists());
+        assert!(first.join("notes.txt").exists());
+
+        update_latest_link(output_dir, &first).unwrap();
+        update_latest_link(output_dir, &second).unwrap();
+        assert_eq!(
+            std::fs::read_link(output_dir.join(LATEST_LINK)).unwrap(),
+            std::path::PathBuf::from("TRACEDIR_1")
+        );
+
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
+
+    #[test]
+    fn nested_template()
+    {
+        let output_dir = std::path::Path::new("/tmp/nested_template");
+        let _ = std::fs::remove_dir_all(output_dir);
+
+        let name = values().expand("{program}/{pid}").unwrap();
+        let trace_dir = create_trace_dir(output_dir, &name, ExistingDir::Fail).unwrap();
+        assert_eq!(trace_dir, output_dir.join("prog/42"));
+
+        update_latest_link(output_dir, &trace_dir).unwrap();
+        let link = output_dir.join(LATEST_LINK);
+        assert_eq!(std::fs::read_link(&link).unwrap(), std::path::PathBuf::from("prog/42"));
+        assert!(link.is_dir(), "Dangling latest link");
+
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
+}
@@ -60,3 +60,586 @@ fn test_program_tokio()
 {
     test_program(env!("CARGO_BIN_EXE_program_tokio"));
 }
+
+#[test]
+fn test_program_crash()
+{
+    let _lock = TEST_MUTEX.lock().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_program_crash"))
+        .env("EXTRAE_FLUSH_ON_CRASH", "true")
+        .output()
+        .expect("Failed to execute program_crash");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(!output.status.success(), "program_crash did not fail");
+
+    #[cfg(feature = "profiling")]
+    {
+        let tracedir = stdout
+            .lines()
+            .find_map(|line| line.split_once("on crash, TraceDir: "))
+            .map(|(_, dir)| dir.to_string())
+            .unwrap_or_else(|| panic!("Unexpected stdout: \n---- \n{}---- \n", stdout));
+
+        let merged = Command::new(env!("CARGO_BIN_EXE_merger"))
+            .args([tracedir.as_str(), "--recover"])
+            .output()
+            .expect("Failed to execute merger");
+
+        assert!(merged.status.success(), "merger failed on the crashed trace");
+        assert!(std::path::Path::new(&tracedir).join("Trace.prv").exists());
+    }
+
+    #[cfg(not(feature = "profiling"))]
+    assert!(!stdout.contains("TraceDir: "), "Unexpected stdout: \n---- \n{}---- \n", stdout);
+}
+
+#[test]
+fn test_program_crash_abort()
+{
+    let _lock = TEST_MUTEX.lock().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_program_crash"))
+        .arg("abort")
+        .env("EXTRAE_FLUSH_ON_CRASH", "true")
+        .output()
+        .expect("Failed to execute program_crash");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(!output.status.success(), "program_crash did not fail");
+
+    // The signal is in the metadata, the worker flushed its buffer.
+    #[cfg(feature = "profiling")]
+    {
+        let tracedir = stdout
+            .lines()
+            .find_map(|line| line.split_once("on crash, TraceDir: "))
+            .map(|(_, dir)| std::path::PathBuf::from(dir))
+            .unwrap_or_else(|| panic!("Unexpected stdout: \n---- \n{}---- \n", stdout));
+
+        let pcf = std::fs::read_to_string(tracedir.join("Trace.pcf")).unwrap();
+        assert!(pcf.contains("#META signal=SIGABRT"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+        assert!(pcf.contains("#META signal_thread=1"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+        assert!(tracedir.join("Trace_2.bin").metadata().is_ok_and(|meta| meta.len() > 0));
+    }
+
+    #[cfg(not(feature = "profiling"))]
+    assert!(!stdout.contains("TraceDir: "), "Unexpected stdout: \n---- \n{}---- \n", stdout);
+}
+
+#[test]
+fn test_program_crash_caught()
+{
+    let _lock = TEST_MUTEX.lock().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_program_crash"))
+        .arg("caught")
+        .env("EXTRAE_FLUSH_ON_CRASH", "true")
+        .output()
+        .expect("Failed to execute program_crash");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    // A panic that does not end the program keeps the normal
+    // finalization and merge.
+    assert!(output.status.success(), "program_crash failed");
+    assert!(!stdout.contains("on crash"), "Unexpected stdout: \n---- \n{}---- \n", stdout);
+
+    #[cfg(feature = "profiling")]
+    {
+        let prv = read_trace_file(&stdout, "Trace.prv");
+        let pcf = read_trace_file(&stdout, "Trace.pcf");
+        assert!(pcf.contains("thread '<unnamed>' panicked"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+        assert!(!prv.is_empty());
+    }
+}
+
+#[test]
+fn test_program_fork()
+{
+    let _lock = TEST_MUTEX.lock().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_program_fork"))
+        .output()
+        .expect("Failed to execute program_fork");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "program_fork exited with an error");
+
+    let tracedirs: Vec<&str> = stdout
+        .lines()
+        .filter_map(|line| line.strip_prefix("# Profiler TraceDir: "))
+        .collect();
+
+    #[cfg(feature = "profiling")]
+    {
+        // The parent and the child
+        assert_eq!(tracedirs.len(), 2, "Unexpected stdout: \n---- \n{}---- \n", stdout);
+
+        let child = tracedirs.iter()
+            .find(|dir| dir.contains("/fork_"))
+            .unwrap_or_else(|| panic!("Unexpected stdout: \n---- \n{}---- \n", stdout));
+
+        let pcf = std::fs::read_to_string(std::path::Path::new(child).join("Trace.pcf")).unwrap();
+        assert!(pcf.contains("#META parent_pid="), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+        assert!(std::path::Path::new(child).join("Trace.prv").exists());
+    }
+
+    #[cfg(not(feature = "profiling"))]
+    assert!(tracedirs.is_empty(), "Unexpected stdout: \n---- \n{}---- \n", stdout);
+}
+
+#[test]
+fn test_extrae_check()
+{
+    let output = Command::new(env!("CARGO_BIN_EXE_extrae-check"))
+        .args(["page-faults", "cyles"])
+        .output()
+        .expect("Failed to execute extrae-check");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    // The typo always fails, page-faults depends on the machine.
+    assert_eq!(output.status.code(), Some(2), "Unexpected stdout: \n---- \n{}---- \n", stdout);
+    assert!(stdout.contains("did you mean 'cycles'?"), "Unexpected stdout: \n---- \n{}---- \n", stdout);
+}
+
+#[test]
+fn test_counters_refused()
+{
+    let _lock = TEST_MUTEX.lock().unwrap();
+
+    // Simulate a container that forbids perf_event_open
+    for (refuse, state) in [("hardware", "degraded"), ("all", "disabled")] {
+        let output = Command::new(env!("CARGO_BIN_EXE_program_threads"))
+            .env("EXTRAE_COUNTERS", "cycles,page-faults")
+            .env("EXTRAE_PERF_REFUSE", refuse)
+            .output()
+            .expect("Failed to execute program_threads");
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+
+        assert!(output.status.success(), "program_threads exited with an error");
+
+        #[cfg(feature = "profiling")]
+        {
+            let pcf = read_trace_file(&stdout, "Trace.pcf");
+
+            // page-faults may also fail with a real perf_event_paranoid
+            assert!(
+                pcf.contains(&format!("#META counters_state={}", state))
+                    || pcf.contains("#META counters_state=disabled"),
+                "Unexpected pcf: \n---- \n{}---- \n", pcf
+            );
+            assert!(pcf.contains("#META counters_dropped=cycles"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+        }
+
+        #[cfg(not(feature = "profiling"))]
+        let _ = (stdout, state);
+    }
+}
+
+#[test]
+fn test_program_sampling()
+{
+    let _lock = TEST_MUTEX.lock().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_program_sampling"))
+        .output()
+        .expect("Failed to execute program_sampling");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "program_sampling exited with an error");
+
+    #[cfg(feature = "profiling")]
+    {
+        let pcf = read_trace_file(&stdout, "Trace.pcf");
+
+        // The sampling events may be forbidden in this machine
+        if !pcf.contains("#META sampling=disabled") {
+            assert!(pcf.contains("#META sampling=task-clock"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+            assert!(pcf.contains("0 30000001 Sampled caller at level 1"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+            assert!(pcf.contains("busy_work"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+        }
+    }
+
+    #[cfg(not(feature = "profiling"))]
+    assert!(!stdout.contains("# Profiler TraceDir: "), "Unexpected stdout: \n---- \n{}---- \n", stdout);
+}
+
+#[test]
+fn test_caller_events()
+{
+    let _lock = TEST_MUTEX.lock().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_program_threads"))
+        .env("EXTRAE_CALLER_DEPTH", "2")
+        .output()
+        .expect("Failed to execute program_threads");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "program_threads exited with an error");
+
+    #[cfg(feature = "profiling")]
+    {
+        let pcf = read_trace_file(&stdout, "Trace.pcf");
+
+        assert!(pcf.contains("0 70000002 Caller at level 2"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+        assert!(pcf.contains("0 80000001 Caller line at level 1"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+        assert!(pcf.contains("program_threads::myfunction1"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+        assert!(pcf.contains("program_threads.rs:"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+    }
+
+    #[cfg(not(feature = "profiling"))]
+    assert!(!stdout.contains("# Profiler TraceDir: "), "Unexpected stdout: \n---- \n{}---- \n", stdout);
+
+    // The pcf gets the callers also without the merge
+    let output = Command::new(env!("CARGO_BIN_EXE_program_threads"))
+        .env("EXTRAE_CALLER_DEPTH", "2")
+        .env("EXTRAE_AUTOMERGE", "false")
+        .output()
+        .expect("Failed to execute program_threads");
+
+    assert!(output.status.success(), "program_threads exited with an error");
+
+    #[cfg(feature = "profiling")]
+    {
+        let pcf = read_trace_file(&String::from_utf8_lossy(&output.stdout), "Trace.pcf");
+
+        assert!(pcf.contains("0 70000002 Caller at level 2"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+        assert!(pcf.contains("program_threads::myfunction1"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+    }
+}
+
+#[test]
+fn test_span_fields()
+{
+    let _lock = TEST_MUTEX.lock().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_program_tokio"))
+        .env("EXTRAE_SPAN_FIELDS", "size,stage,retries,cached")
+        .output()
+        .expect("Failed to execute program_tokio");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "program_tokio exited with an error");
+
+    #[cfg(feature = "profiling")]
+    {
+        let pcf = read_trace_file(&stdout, "Trace.pcf");
+
+        // The numeric field is the value, the string gets a name
+        let size_id = event_id(&pcf, "size");
+        assert!(pcf.contains("stage:sleeping"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+
+        assert!(pcf.contains("cached:false"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+
+        let prv = read_trace_file(&stdout, "Trace.prv");
+        assert!(prv.contains(&format!(":{}:5", size_id)), "Unexpected prv: \n---- \n{}---- \n", prv);
+
+        // A 0 would end the event in Paraver, it is not recorded
+        let retries_id = event_id(&pcf, "retries");
+        assert!(!prv.contains(&format!(":{}:0", retries_id)), "Unexpected prv: \n---- \n{}---- \n", prv);
+    }
+
+    #[cfg(not(feature = "profiling"))]
+    assert!(!stdout.contains("# Profiler TraceDir: "), "Unexpected stdout: \n---- \n{}---- \n", stdout);
+}
+
+#[test]
+fn test_span_instances()
+{
+    let _lock = TEST_MUTEX.lock().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_program_tokio"))
+        .output()
+        .expect("Failed to execute program_tokio");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "program_tokio exited with an error");
+
+    #[cfg(feature = "profiling")]
+    {
+        let pcf = read_trace_file(&stdout, "Trace.pcf");
+        let task1_id = event_id(&pcf, "task1");
+
+        // The two concurrent task1 have different values
+        let prv = read_trace_file(&stdout, "Trace.prv");
+        assert!(prv.contains(&format!(":{}:1\n", task1_id)), "Unexpected prv: \n---- \n{}---- \n", prv);
+        assert!(prv.contains(&format!(":{}:2\n", task1_id)), "Unexpected prv: \n---- \n{}---- \n", prv);
+    }
+
+    #[cfg(not(feature = "profiling"))]
+    assert!(!stdout.contains("# Profiler TraceDir: "), "Unexpected stdout: \n---- \n{}---- \n", stdout);
+}
+
+#[test]
+fn test_program_layer()
+{
+    let _lock = TEST_MUTEX.lock().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_program_layer"))
+        .output()
+        .expect("Failed to execute program_layer");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "program_layer exited with an error");
+
+    // The fmt layer still works next to the extrae one
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Layer task started"), "Unexpected stderr: \n---- \n{}---- \n", stderr);
+
+    #[cfg(feature = "profiling")]
+    {
+        // The debug span is removed by the LevelFilter
+        let pcf = read_trace_file(&stdout, "Trace.pcf");
+        assert!(pcf.contains(" layer_task\n"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+        assert!(pcf.contains(":Layer task started"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+        assert!(!pcf.contains("verbose_step"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+    }
+
+    #[cfg(not(feature = "profiling"))]
+    assert!(!stdout.contains("# Profiler TraceDir: "), "Unexpected stdout: \n---- \n{}---- \n", stdout);
+}
+
+#[test]
+fn test_tokio_tasks()
+{
+    let _lock = TEST_MUTEX.lock().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_program_tokio_tasks"))
+        .output()
+        .expect("Failed to execute program_tokio_tasks");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "program_tokio_tasks exited with an error");
+
+    // Tokio only creates the runtime.spawn spans with tokio_unstable
+    #[cfg(all(feature = "profiling", tokio_unstable))]
+    {
+        // The runtime.spawn spans become task events
+        let pcf = read_trace_file(&stdout, "Trace.pcf");
+        assert!(!pcf.contains("runtime.spawn\n"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+        assert!(pcf.contains("tokio_task_spawn:task worker "), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+
+        let task_id = event_id(&pcf, "tokio_task");
+
+        // Every task polled has its id as value
+        let prv = read_trace_file(&stdout, "Trace.prv");
+        let polled: std::collections::BTreeSet<&str> = prv
+            .split(&format!(":{}:", task_id))
+            .skip(1)
+            .filter_map(|rest| rest.split([':', '\n']).next())
+            .filter(|value| *value != "0")
+            .collect();
+        assert_eq!(polled.len(), 5, "Unexpected prv: \n---- \n{}---- \n", prv);
+    }
+
+    #[cfg(all(feature = "profiling", not(tokio_unstable)))]
+    {
+        let pcf = read_trace_file(&stdout, "Trace.pcf");
+        assert!(!pcf.contains("tokio_task_spawn:"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+    }
+
+    #[cfg(not(feature = "profiling"))]
+    assert!(!stdout.contains("# Profiler TraceDir: "), "Unexpected stdout: \n---- \n{}---- \n", stdout);
+}
+
+#[test]
+fn test_runtime_metrics()
+{
+    let _lock = TEST_MUTEX.lock().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_program_runtime_metrics"))
+        .output()
+        .expect("Failed to execute program_runtime_metrics");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "program_runtime_metrics exited with an error");
+
+    #[cfg(feature = "profiling")]
+    {
+        let pcf = read_trace_file(&stdout, "Trace.pcf");
+        for name in ["tokio_alive_tasks", "tokio_global_queue_depth", "tokio_worker_0_busy_us", "tokio_worker_1_parks"] {
+            assert!(pcf.contains(&format!(" {}\n", name)), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+        }
+
+        let alive_id = event_id(&pcf, "tokio_alive_tasks");
+
+        // The sampler thread has its own row (the main thread is 1)
+        let prv = read_trace_file(&stdout, "Trace.prv");
+        let samples: Vec<&str> = prv.lines()
+            .filter(|line| line.contains(&format!(":{}:", alive_id)))
+            .collect();
+        assert!(samples.len() > 2, "Unexpected prv: \n---- \n{}---- \n", prv);
+        assert!(samples.iter().all(|line| line.starts_with("2:0:1:1:2:")), "Unexpected prv: \n---- \n{}---- \n", prv);
+    }
+
+    #[cfg(not(feature = "profiling"))]
+    assert!(!stdout.contains("# Profiler TraceDir: "), "Unexpected stdout: \n---- \n{}---- \n", stdout);
+}
+
+/// Read a file of the trace directory printed by the program.
+#[cfg(feature = "profiling")]
+fn read_trace_file(stdout: &str, file: &str) -> String
+{
+    let tracedir = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("# Profiler TraceDir: "))
+        .unwrap_or_else(|| panic!("Unexpected stdout: \n---- \n{}---- \n", stdout));
+
+    std::fs::read_to_string(std::path::Path::new(tracedir).join(file))
+        .unwrap_or_else(|err| panic!("Failed to read {}: {}", file, err))
+}
+
+/// Find the id of the event type with this name in the pcf.
+#[cfg(feature = "profiling")]
+fn event_id<'a>(pcf: &'a str, name: &str) -> &'a str
+{
+    pcf.lines()
+        .find_map(|line| line.strip_prefix("0 ")?.strip_suffix(name)?.strip_suffix(' '))
+        .unwrap_or_else(|| panic!("{} not found in pcf: \n---- \n{}---- \n", name, pcf))
+}
+
+/// Check that the regions of these events start and end in the same
+/// thread: the values in every thread alternate between 1 and 0.
+#[cfg(feature = "profiling")]
+fn assert_balanced_regions(pcf: &str, prv: &str, names: &[&str])
+{
+    for name in names {
+        let id = event_id(pcf, name);
+
+        let mut started = std::collections::HashMap::<&str, bool>::new();
+
+        for line in prv.lines().filter(|line| line.starts_with("2:")) {
+            let fields: Vec<&str> = line.split(':').collect();
+            let thread = fields[4];
+
+            for pair in fields[6..].chunks(2).filter(|pair| pair[0] == id) {
+                let start = pair[1] == "1";
+                assert!(start || pair[1] == "0", "{} unexpected value in thread {}: \n---- \n{}---- \n", name, thread, prv);
+                let previous = started.insert(thread, start).unwrap_or(false);
+                assert_ne!(previous, start, "{} unbalanced in thread {}: \n---- \n{}---- \n", name, thread, prv);
+            }
+        }
+
+        assert!(!started.is_empty(), "{} not found: \n---- \n{}---- \n", name, prv);
+        assert!(started.values().all(|started| !started), "{} not ended: \n---- \n{}---- \n", name, prv);
+    }
+}
+
+#[test]
+fn test_async_profile()
+{
+    let _lock = TEST_MUTEX.lock().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_program_async"))
+        .output()
+        .expect("Failed to execute program_async");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "program_async exited with an error");
+    assert_eq!(stdout.matches("Task result: Ok(").count(), 6, "Unexpected stdout: \n---- \n{}---- \n", stdout);
+
+    #[cfg(feature = "profiling")]
+    {
+        let pcf = read_trace_file(&stdout, "Trace.pcf");
+
+        // Every poll starts and ends in the same thread
+        let prv = read_trace_file(&stdout, "Trace.prv");
+        assert_balanced_regions(&pcf, &prv, &["run", "async_task", "step"]);
+
+        // The pending polls are marked with a point event
+        let awaiting_id = event_id(&pcf, "async_task:awaiting");
+        assert!(pcf.lines().any(|line| line.strip_prefix("#META point_events=")
+                .is_some_and(|ids| ids.split(',').any(|id| id == awaiting_id))),
+            "Unexpected pcf: \n---- \n{}---- \n", pcf);
+        assert!(prv.contains(&format!(":{}:1", awaiting_id)), "Unexpected prv: \n---- \n{}---- \n", prv);
+    }
+
+    #[cfg(not(feature = "profiling"))]
+    assert!(!stdout.contains("# Profiler TraceDir: "), "Unexpected stdout: \n---- \n{}---- \n", stdout);
+}
+
+#[test]
+fn test_future_ext()
+{
+    let _lock = TEST_MUTEX.lock().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_program_future_ext"))
+        .output()
+        .expect("Failed to execute program_future_ext");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "program_future_ext exited with an error");
+    assert!(stdout.contains("Total: 46\n"), "Unexpected stdout: \n---- \n{}---- \n", stdout);
+
+    #[cfg(feature = "profiling")]
+    {
+        // The events show where they were instrumented
+        let pcf = read_trace_file(&stdout, "Trace.pcf");
+        assert!(!pcf.contains("src/future.rs"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+
+        let prv = read_trace_file(&stdout, "Trace.prv");
+        assert_balanced_regions(&pcf, &prv, &["producer", "received_stream", "later"]);
+    }
+
+    #[cfg(not(feature = "profiling"))]
+    assert!(!stdout.contains("# Profiler TraceDir: "), "Unexpected stdout: \n---- \n{}---- \n", stdout);
+}
+
+#[test]
+#[cfg(feature = "log")]
+fn test_program_log()
+{
+    let _lock = TEST_MUTEX.lock().unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_program_log"))
+        .output()
+        .expect("Failed to execute program_log");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(output.status.success(), "program_log exited with an error");
+
+    // The records are forwarded to the other logger
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("[INFO] Starting\n"), "Unexpected stderr: \n---- \n{}---- \n", stderr);
+    assert!(!stderr.contains("Working on"), "Unexpected stderr: \n---- \n{}---- \n", stderr);
+
+    #[cfg(feature = "profiling")]
+    {
+        // Every call site is a value, the messages with arguments are
+        // named with the location, or share the dynamic value without
+        // location. The trace record is filtered.
+        let pcf = read_trace_file(&stdout, "Trace.pcf");
+        for name in ["log:Starting", "log:extrae-rs/bin/program_log.rs:26", "log_level:DEBUG", "log:bridge Bridged", "log:dynamic"] {
+            assert!(pcf.contains(&format!(" {}\n", name)), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+        }
+        assert!(!pcf.contains("log:Working on"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+        assert!(!pcf.contains("log:Done"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+        assert!(!pcf.contains("Bridged 0"), "Unexpected pcf: \n---- \n{}---- \n", pcf);
+
+        let log_id = event_id(&pcf, "log");
+
+        // Starting, 5 times Working on, the total and 4 bridged
+        let prv = read_trace_file(&stdout, "Trace.prv");
+        assert_eq!(prv.matches(&format!(":{}:", log_id)).count(), 11, "Unexpected prv: \n---- \n{}---- \n", prv);
+    }
+
+    #[cfg(not(feature = "profiling"))]
+    assert!(!stdout.contains("# Profiler TraceDir: "), "Unexpected stdout: \n---- \n{}---- \n", stdout);
+}